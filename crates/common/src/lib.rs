@@ -0,0 +1,102 @@
+//! Definitions shared between volumed, mountd and the CSI driver.
+//!
+//! Note: This has no dependencies of its own, so that the daemons can share it
+//! without depending on each other.
+
+use std::{ffi::OsString, os::unix::ffi::OsStringExt, path::PathBuf};
+
+/// Filesystem used when a request does not specify one
+pub const DEFAULT_FS_TYPE: &str = "xfs";
+
+/// Filesystems that volumes can be formatted with
+pub const SUPPORTED_FS_TYPES: [&str; 3] = ["xfs", "ext4", "btrfs"];
+
+/// Round a size in bytes up to a whole number of extents, as allocated by LVM
+pub fn round_to_extents(bytes: u64, extent_size: u64) -> u64 {
+    if extent_size == 0 {
+        return bytes;
+    }
+
+    match bytes % extent_size {
+        0 => bytes,
+        remainder => bytes + (extent_size - remainder),
+    }
+}
+
+/// Undo the octal escaping used by the kernel for whitespace (and backslashes)
+/// in the mount table.
+///
+/// Note: Paths are not necessarily valid UTF-8, so this works on the raw bytes.
+pub fn unescape(column: &str) -> PathBuf {
+    let mut result = Vec::with_capacity(column.len());
+    let mut bytes = column.as_bytes().iter().copied();
+
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            result.push(byte);
+            continue;
+        }
+
+        let code: Vec<u8> = bytes.by_ref().take(3).collect();
+        match std::str::from_utf8(&code)
+            .ok()
+            .and_then(|code| u8::from_str_radix(code, 8).ok())
+        {
+            Some(unescaped) => result.push(unescaped),
+            None => {
+                result.push(byte);
+                result.extend(code);
+            }
+        }
+    }
+
+    OsString::from_vec(result).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::*;
+
+    #[test]
+    fn rounds_up_to_whole_extents() {
+        const EXTENT: u64 = 4 * 1024 * 1024;
+
+        for (bytes, extent_size, rounded) in [
+            (0, EXTENT, 0),
+            (1, EXTENT, EXTENT),
+            (EXTENT - 1, EXTENT, EXTENT),
+            (EXTENT, EXTENT, EXTENT),
+            (EXTENT + 1, EXTENT, 2 * EXTENT),
+            (3 * EXTENT, EXTENT, 3 * EXTENT),
+            // Without an extent size, there is nothing to round to
+            (12345, 0, 12345),
+        ] {
+            assert_eq!(
+                round_to_extents(bytes, extent_size),
+                rounded,
+                "{} bytes in extents of {}",
+                bytes,
+                extent_size
+            );
+        }
+    }
+
+    #[test]
+    fn unescapes_mount_table_columns() {
+        for (column, path) in [
+            ("/dev/mapper/vg-lv", &b"/dev/mapper/vg-lv"[..]),
+            ("/mnt/with\\040space", b"/mnt/with space"),
+            ("/mnt/tab\\011and\\012newline", b"/mnt/tab\tand\nnewline"),
+            ("/mnt/back\\134slash", b"/mnt/back\\slash"),
+            // Not valid UTF-8
+            ("/mnt/\\377", b"/mnt/\xff"),
+            // Invalid escapes are kept as is
+            ("/mnt/\\9x", b"/mnt/\\9x"),
+            ("/mnt/\\", b"/mnt/\\"),
+        ] {
+            assert_eq!(unescape(column).as_os_str().as_bytes(), path, "{}", column);
+        }
+    }
+}
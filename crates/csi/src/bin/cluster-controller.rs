@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use futures_util::FutureExt;
+use tokio::net::UnixListener;
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::transport::Server;
+
+use rlvm::{
+    cluster::{ClusterController, Registry},
+    identity::RLVMIdentity,
+};
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// Path to the registry of nodes to schedule volumes across
+    #[clap(default_value = "/etc/rlvm/registry.yaml")]
+    registry: PathBuf,
+
+    /// Path to the listening socket
+    #[clap(short, long, default_value = "/run/rlvm/controller.sock")]
+    socket_path: PathBuf,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Initialize the env_logger
+    env_logger::init();
+
+    // Parse the CLI options
+    let args = Cli::parse();
+
+    // Attempt to parse the registry
+    let registry_file = std::fs::File::open(&args.registry).map_err(|err| {
+        format!(
+            "could not open registry {}: {}",
+            args.registry.to_string_lossy(),
+            err
+        )
+    })?;
+
+    let registry: Registry = serde_yaml::from_reader(registry_file).map_err(|err| {
+        format!(
+            "invalid registry at {}: {}",
+            args.registry.to_string_lossy(),
+            err
+        )
+    })?;
+
+    log::info!("Found registry: {:?}", registry);
+
+    let controller = ClusterController::new(registry)?;
+    let identity = RLVMIdentity::new(controller.verifier());
+
+    // Create the unix socket for communication
+    let sock = UnixListener::bind(&args.socket_path)?;
+    let sock_stream = UnixListenerStream::new(sock);
+
+    // Set up the server
+    log::info!(
+        "Starting the rlvm cluster controller service at `{}`",
+        args.socket_path.to_string_lossy()
+    );
+
+    // Handle SIGINT cleanly by cleaning up the socket when killed
+    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+    ctrlc::set_handler(move || tx.blocking_send(()).expect("could not send sigint"))
+        .expect("could not set Ctrl-C handler");
+
+    // Start listening
+    Server::builder()
+        .add_service(controller.into_service())
+        .add_service(identity.into_service())
+        // Serve until we get a Ctrl^C (or are killed)
+        .serve_with_incoming_shutdown(sock_stream, rx.recv().map(|_| ()))
+        .await?;
+
+    // Clean up the socket file
+    log::info!("Cleaning up socket file...");
+    tokio::fs::remove_file(&args.socket_path).await?;
+
+    Ok(())
+}
@@ -10,18 +10,34 @@ use tonic::{
 };
 use tower::service_fn;
 use uuid::Uuid;
-use volumed::spec::volume_service_client::VolumeServiceClient;
+use volumed::{auth::WithToken, spec::volume_service_client::VolumeServiceClient};
 
 use rlvm::{
     controller::RLVMController,
     identity::{RLVMIdentity, Verifier},
+    node_id,
+    topology::{self, Segment},
 };
 
 #[derive(Debug, Parser)]
 struct Cli {
-    /// Unique ID for this node
-    #[clap(short, long, default_value_t = Uuid::new_v4())]
-    node_id: Uuid,
+    /// Unique ID for this node, defaults to the persisted ID or one derived from the
+    /// machine ID
+    #[clap(short, long)]
+    node_id: Option<Uuid>,
+
+    /// Path to the file persisting the ID of this node
+    #[clap(long, default_value = node_id::DEFAULT_STATE_FILE)]
+    state_file: PathBuf,
+
+    /// Key of the topology segment identifying this node
+    #[clap(long, default_value = topology::DEFAULT_HOST_KEY)]
+    host_topology_key: String,
+
+    /// Additional topology segment of this node (e.g. `zone=eu-west-1a`), can be
+    /// repeated. Must be the same for the controller and node plugins
+    #[clap(long = "topology", value_name = "KEY=VALUE")]
+    topology: Vec<Segment>,
 
     /// Path to the listening socket
     #[clap(short, long, default_value = "/run/rlvm/controller.sock")]
@@ -39,6 +55,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     // Parse the CLI options
     let args = Cli::parse();
 
+    // Make sure that this node keeps its identity across restarts
+    let node_id = node_id::resolve(args.node_id, &args.state_file)?;
+    log::info!("Using node ID `{}`", node_id);
+
+    let topology = topology::node_topology(node_id, &args.host_topology_key, &args.topology);
+    log::info!("Using topology {:?}", topology.segments);
+
     // Create the unix socket for communication
     let sock = UnixListener::bind(&args.socket_path)?;
     let sock_stream = UnixListenerStream::new(sock);
@@ -49,7 +72,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         args.socket_path.to_string_lossy()
     );
 
-    let controller = RLVMController::new(args.node_id);
+    let controller = RLVMController::new(topology);
     let identity = RLVMIdentity::new(Verifier::Controller);
 
     // Handle SIGINT cleanly by cleaning up the socket when killed
@@ -86,8 +109,8 @@ async fn client_injector(
         .await
         .expect("could not connect to volumed socket");
 
-    // Create a client for the volumed service
-    let client = VolumeServiceClient::new(channel);
+    // Create a client for the volumed service, which needs no token over the socket
+    let client = VolumeServiceClient::with_interceptor(channel, WithToken::default());
 
     move |mut req| {
         // Inject the client into the request
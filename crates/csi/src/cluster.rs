@@ -0,0 +1,1035 @@
+//! Controller scheduling volumes across the volumed instances of many nodes.
+//!
+//! Instead of running one controller per node, a single controller connects to the
+//! volumed of every node listed in a [Registry] (over TCP) and delegates each request
+//! to the [RLVMController] of the node it concerns. Volume and snapshot IDs are
+//! qualified with the ID of the node owning them (`<node>:<uuid>`), so that later
+//! requests can be routed back to it. Plain IDs (e.g. of volumes created by a single
+//! node controller) are still accepted, by looking them up on every node.
+
+use std::{cmp::Reverse, collections::HashMap, net::IpAddr, path::PathBuf};
+
+use serde::Deserialize;
+use tonic::{
+    transport::{Endpoint, Uri},
+    Code, Request, Response, Status,
+};
+use uuid::Uuid;
+use volumed::{
+    auth::{self, Client, WithToken},
+    spec::{
+        get_lv_request::Identifier, get_snapshot_request::Identifier as SnapshotIdentifier,
+        volume_service_client::VolumeServiceClient, GetFreeBytesRequest, GetLvRequest,
+        GetSnapshotRequest,
+    },
+};
+
+use crate::controller::{self, RLVMController, VOLUME_GROUP_PARAMETER};
+use crate::csi::v1_7_0::{
+    controller_server::{Controller, ControllerServer},
+    volume_content_source::Type as ContentType,
+    ControllerExpandVolumeRequest, ControllerExpandVolumeResponse,
+    ControllerGetCapabilitiesRequest, ControllerGetCapabilitiesResponse,
+    ControllerGetVolumeRequest, ControllerGetVolumeResponse, ControllerPublishVolumeRequest,
+    ControllerPublishVolumeResponse, ControllerUnpublishVolumeRequest,
+    ControllerUnpublishVolumeResponse, CreateSnapshotRequest, CreateSnapshotResponse,
+    CreateVolumeRequest, CreateVolumeResponse, DeleteSnapshotRequest, DeleteSnapshotResponse,
+    DeleteVolumeRequest, DeleteVolumeResponse, GetCapacityRequest, GetCapacityResponse,
+    ListSnapshotsRequest, ListSnapshotsResponse, ListVolumesRequest, ListVolumesResponse, Snapshot,
+    Topology, ValidateVolumeCapabilitiesRequest, ValidateVolumeCapabilitiesResponse, Volume,
+};
+use crate::identity::Verifier;
+use crate::topology::{self, Segment};
+use crate::Redact;
+
+/// Separator between the node and the volumed parts of IDs (and pagination tokens)
+const ID_SEPARATOR: char = ':';
+
+/// The nodes to schedule volumes across
+#[derive(Clone, Debug, Deserialize)]
+pub struct Registry {
+    /// Key of the topology segment identifying a node, which must match the one
+    /// used by the node plugins
+    #[serde(default = "default_host_topology_key")]
+    pub host_topology_key: String,
+
+    /// Path to a file holding the token to authenticate with to volumed, which
+    /// must match the `--token-file` of every node
+    #[serde(default)]
+    pub token_file: Option<PathBuf>,
+
+    /// Path to the (PEM encoded) certificate of the authority which signed the
+    /// certificates of every volumed, required for `https` endpoints
+    #[serde(default)]
+    pub tls_ca_file: Option<PathBuf>,
+
+    pub nodes: Vec<NodeConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct NodeConfig {
+    /// The ID of the node, as used by its node plugin
+    pub id: Uuid,
+
+    /// The address of the volumed of the node (e.g. `https://10.0.0.1:50051`), which
+    /// must use TLS unless it is a loopback address
+    pub endpoint: String,
+
+    /// Name to verify the certificate of the volumed against, if not the host of
+    /// its endpoint
+    #[serde(default)]
+    pub tls_domain: Option<String>,
+
+    /// Additional topology segments of the node (e.g. `zone: eu-west-1a`)
+    #[serde(default)]
+    pub topology: HashMap<String, String>,
+}
+
+fn default_host_topology_key() -> String {
+    topology::DEFAULT_HOST_KEY.into()
+}
+
+/// The kinds of resources that can be looked up on the nodes
+#[derive(Clone, Copy, Debug)]
+enum Resource {
+    Volume,
+    Snapshot,
+}
+
+impl Resource {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Volume => "volume",
+            Self::Snapshot => "snapshot",
+        }
+    }
+}
+
+/// A node of the cluster, along with the controller handling its volumes
+#[derive(Debug)]
+struct ClusterNode {
+    id: Uuid,
+    topology: Topology,
+    client: Client,
+    controller: RLVMController,
+}
+
+impl ClusterNode {
+    /// Wrap a request for the controller of the node
+    fn request<T>(&self, message: T) -> Request<T> {
+        let mut request = Request::new(message);
+        request.extensions_mut().insert(self.client.clone());
+
+        request
+    }
+
+    /// Qualify the IDs of a volume (and of its content source) with the node
+    fn encode_volume(&self, mut volume: Volume) -> Volume {
+        volume.volume_id = encode_id(self.id, &volume.volume_id);
+
+        match volume
+            .content_source
+            .as_mut()
+            .and_then(|source| source.r#type.as_mut())
+        {
+            Some(ContentType::Volume(source)) => {
+                source.volume_id = encode_id(self.id, &source.volume_id)
+            }
+            Some(ContentType::Snapshot(source)) => {
+                source.snapshot_id = encode_id(self.id, &source.snapshot_id)
+            }
+            None => {}
+        }
+
+        volume
+    }
+
+    /// Qualify the IDs of a snapshot (and of its source volume) with the node
+    fn encode_snapshot(&self, mut snapshot: Snapshot) -> Snapshot {
+        snapshot.snapshot_id = encode_id(self.id, &snapshot.snapshot_id);
+        snapshot.source_volume_id = encode_id(self.id, &snapshot.source_volume_id);
+
+        snapshot
+    }
+
+    /// Whether the resource with the specified (plain) ID lives on this node
+    async fn has(&self, resource: Resource, id: &str) -> Result<bool, Status> {
+        let mut client = self.client.clone();
+        let found = match resource {
+            Resource::Volume => client
+                .get_logical_volume(Request::new(GetLvRequest {
+                    identifier: Some(Identifier::Uuid(id.to_string())),
+                    volume_group: String::new(),
+                }))
+                .await
+                .map(|_| ()),
+            Resource::Snapshot => client
+                .get_snapshot(Request::new(GetSnapshotRequest {
+                    identifier: Some(SnapshotIdentifier::Uuid(id.to_string())),
+                    volume_group: String::new(),
+                }))
+                .await
+                .map(|_| ()),
+        };
+
+        match found {
+            Ok(()) => Ok(true),
+            Err(status) if status.code() == Code::NotFound => Ok(false),
+            Err(status) => Err(status),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ClusterController {
+    /// The nodes of the cluster, ordered by ID so that listings are stable
+    nodes: Vec<ClusterNode>,
+}
+
+impl ClusterController {
+    /// Create a controller for the nodes of a registry.
+    ///
+    /// Note: Nodes are connected to lazily, so unreachable nodes do not prevent
+    /// the controller from starting.
+    pub fn new(registry: Registry) -> Result<Self, String> {
+        if registry.nodes.is_empty() {
+            return Err("registry must list at least one node".into());
+        }
+
+        let token = match &registry.token_file {
+            Some(path) => WithToken::new(&auth::read_token(path)?)?,
+            None => WithToken::default(),
+        };
+        let tls = registry
+            .tls_ca_file
+            .as_deref()
+            .map(auth::read_client_tls)
+            .transpose()?;
+
+        let mut nodes = registry
+            .nodes
+            .into_iter()
+            .map(|node| {
+                let mut endpoint =
+                    Endpoint::from_shared(node.endpoint.clone()).map_err(|err| {
+                        format!(
+                            "invalid endpoint `{}` for node `{}`: {}",
+                            node.endpoint, node.id, err
+                        )
+                    })?;
+
+                // The token (as well as the passphrases of encrypted volumes) must not
+                //  cross the network in plaintext
+                if endpoint.uri().scheme_str() == Some("https") {
+                    let mut config = tls.clone().ok_or_else(|| {
+                        format!(
+                            "endpoint `{}` for node `{}` uses https, but the registry has no `tls_ca_file`",
+                            node.endpoint, node.id
+                        )
+                    })?;
+                    if let Some(domain) = &node.tls_domain {
+                        config = config.domain_name(domain);
+                    }
+
+                    endpoint = endpoint.tls_config(config).map_err(|err| {
+                        format!("invalid TLS config for node `{}`: {}", node.id, err)
+                    })?;
+                } else if !is_loopback(endpoint.uri()) {
+                    return Err(format!(
+                        "endpoint `{}` for node `{}` must use https, as it is not a loopback address",
+                        node.endpoint, node.id
+                    ));
+                }
+
+                let segments: Vec<Segment> = node
+                    .topology
+                    .into_iter()
+                    .map(|(key, value)| Segment { key, value })
+                    .collect();
+                let topology =
+                    topology::node_topology(node.id, &registry.host_topology_key, &segments);
+
+                Ok(ClusterNode {
+                    id: node.id,
+                    controller: RLVMController::new(topology.clone()),
+                    topology,
+                    client: VolumeServiceClient::with_interceptor(
+                        endpoint.connect_lazy(),
+                        token.clone(),
+                    ),
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        nodes.sort_by_key(|node| node.id);
+        if let Some(pair) = nodes.windows(2).find(|pair| pair[0].id == pair[1].id) {
+            return Err(format!("node `{}` is listed more than once", pair[0].id));
+        }
+
+        Ok(Self { nodes })
+    }
+
+    pub fn into_service(self) -> ControllerServer<Self> {
+        ControllerServer::new(self)
+    }
+
+    /// Get a [Verifier] checking that the cluster can serve requests
+    pub fn verifier(&self) -> Verifier {
+        Verifier::Cluster(self.nodes.iter().map(|node| node.client.clone()).collect())
+    }
+
+    /// Get the node with the specified ID
+    fn node(&self, id: Uuid) -> Result<&ClusterNode, Status> {
+        self.nodes
+            .iter()
+            .find(|node| node.id == id)
+            .ok_or_else(|| Status::not_found(format!("unknown node `{}`", id)))
+    }
+
+    /// Find the node owning a resource, along with the ID it is known by on that node.
+    ///
+    /// Qualified IDs are routed to their node as-is, while plain IDs are looked up
+    /// on every node. Fails with UNAVAILABLE if a plain ID could not be found, but
+    /// some of the nodes could not be asked.
+    async fn owner(
+        &self,
+        resource: Resource,
+        id: &str,
+    ) -> Result<Option<(&ClusterNode, String)>, Status> {
+        let (node_id, id) = decode_id(id)?;
+        if let Some(node_id) = node_id {
+            return Ok(Some((self.node(node_id)?, id.to_string())));
+        }
+
+        let mut unreachable = None;
+        for node in &self.nodes {
+            match node.has(resource, id).await {
+                Ok(true) => return Ok(Some((node, id.to_string()))),
+                Ok(false) => {}
+                Err(status) => unreachable = Some((node.id, status)),
+            }
+        }
+
+        match unreachable {
+            Some((node_id, status)) => Err(Status::unavailable(format!(
+                "could not look up {} `{}` on node `{}`: {}",
+                resource.name(),
+                id,
+                node_id,
+                status.message()
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// Like [Self::owner], but fails with NOT_FOUND if the resource does not exist
+    async fn existing_owner(
+        &self,
+        resource: Resource,
+        id: &str,
+    ) -> Result<(&ClusterNode, String), Status> {
+        self.owner(resource, id).await?.ok_or_else(|| {
+            Status::not_found(format!("could not find {} `{}`", resource.name(), id))
+        })
+    }
+
+    /// Find the node holding the volume with the specified CSI name, if any.
+    ///
+    /// Note: All nodes have to be reachable, as creating the volume again on
+    /// another node would leak the existing one.
+    async fn find_volume(&self, name: &str) -> Result<Option<&ClusterNode>, Status> {
+        for node in &self.nodes {
+            let volume = controller::find_volume(&mut node.client.clone(), name)
+                .await
+                .map_err(|status| match status.code() {
+                    Code::AlreadyExists => status,
+                    _ => Status::unavailable(format!(
+                        "could not check node `{}` for existing volume `{}`: {}",
+                        node.id,
+                        name,
+                        status.message()
+                    )),
+                })?;
+
+            if volume.is_some() {
+                return Ok(Some(node));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Rank the nodes which a new volume can be placed on, best first.
+    ///
+    /// Nodes have to satisfy the accessibility requirements of the request, and
+    /// are ordered by the first preferred topology they match, then by the free
+    /// capacity of the requested volume group.
+    async fn place(&self, req: &CreateVolumeRequest) -> Result<Vec<&ClusterNode>, Status> {
+        let volume_group = req
+            .parameters
+            .get(VOLUME_GROUP_PARAMETER)
+            .cloned()
+            .unwrap_or_default();
+        let preferred = req
+            .accessibility_requirements
+            .as_ref()
+            .map(|requirements| requirements.preferred.as_slice())
+            .unwrap_or_default();
+
+        let mut rejection = None;
+        let mut candidates = Vec::new();
+        for node in &self.nodes {
+            if let Some(requirements) = &req.accessibility_requirements {
+                if let Err(status) = topology::check_requirements(&node.topology, requirements) {
+                    rejection = rejection.or(Some(status));
+                    continue;
+                }
+            }
+
+            // Skip nodes which are unreachable, or do not have the volume group
+            let free = node
+                .client
+                .clone()
+                .get_free_bytes(Request::new(GetFreeBytesRequest {
+                    volume_group: volume_group.clone(),
+                }))
+                .await;
+            let free = match free {
+                Ok(free) => free.into_inner().bytes_free,
+                Err(status) => {
+                    log::warn!(
+                        "not placing volume `{}` on node `{}`: {}",
+                        req.name,
+                        node.id,
+                        status.message()
+                    );
+
+                    rejection = rejection.or(Some(status));
+                    continue;
+                }
+            };
+
+            let preference = preferred
+                .iter()
+                .position(|topology| topology::matches(&node.topology, topology))
+                .unwrap_or(preferred.len());
+
+            candidates.push((preference, Reverse(free), node));
+        }
+
+        if candidates.is_empty() {
+            return Err(rejection
+                .unwrap_or_else(|| Status::resource_exhausted("no node can hold the volume")));
+        }
+
+        candidates.sort_by_key(|(preference, free, _)| (*preference, *free));
+        Ok(candidates.into_iter().map(|(_, _, node)| node).collect())
+    }
+}
+
+/// Qualify an ID (or pagination token) of a node with the node
+fn encode_id(node: Uuid, id: &str) -> String {
+    format!("{}{}{}", node, ID_SEPARATOR, id)
+}
+
+/// Split an ID into the node owning it (if qualified) and the ID known to the node
+pub(crate) fn decode_id(id: &str) -> Result<(Option<Uuid>, &str), Status> {
+    match id.split_once(ID_SEPARATOR) {
+        None => Ok((None, id)),
+        Some((node, rest)) => Uuid::parse_str(node)
+            .map(|node| (Some(node), rest))
+            .map_err(|err| {
+                Status::not_found(format!("invalid node `{}` in ID `{}`: {}", node, id, err))
+            }),
+    }
+}
+
+/// Strip the node from a (possibly qualified) ID
+fn strip_node(id: &str) -> Result<String, Status> {
+    decode_id(id).map(|(_, id)| id.to_string())
+}
+
+/// Parse a pagination token (`<node>:<token of the node>`) into the position of the
+/// node among `node_ids` and the token to pass to it
+fn parse_token<I>(node_ids: I, token: &str) -> Result<(usize, String), Status>
+where
+    I: IntoIterator<Item = Uuid>,
+{
+    if token.is_empty() {
+        return Ok((0, String::new()));
+    }
+
+    let position = match token.split_once(ID_SEPARATOR) {
+        Some((node, token)) => node_ids
+            .into_iter()
+            .position(|candidate| candidate.to_string() == node)
+            .map(|position| (position, token.to_string())),
+        None => None,
+    };
+
+    position.ok_or_else(|| {
+        Status::aborted(format!(
+            "`starting_token` does not refer to a known node: `{}`",
+            token
+        ))
+    })
+}
+
+/// Get the number of entries left in a page of `max_entries`, where zero means all
+fn remaining_entries(max_entries: i32, listed: usize) -> Result<i32, Status> {
+    if max_entries < 0 {
+        return Err(Status::invalid_argument(
+            "`max_entries` must be a valid positive integer",
+        ));
+    }
+
+    Ok(match max_entries {
+        0 => 0,
+        max_entries => max_entries - listed as i32,
+    })
+}
+
+/// Whether a URI refers to the current host, which can be reached without TLS
+fn is_loopback(uri: &Uri) -> bool {
+    match uri.host() {
+        Some("localhost") => true,
+        Some(host) => host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .map(|ip| ip.is_loopback())
+            .unwrap_or_default(),
+        None => false,
+    }
+}
+
+#[tonic::async_trait]
+impl Controller for ClusterController {
+    async fn list_volumes(
+        &self,
+        request: Request<ListVolumesRequest>,
+    ) -> Result<Response<ListVolumesResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!("got list volume request with: {:?}", req);
+
+        // Nodes are listed one after the other, resuming from the node in the token
+        let nodes: Vec<&ClusterNode> = self.nodes.iter().collect();
+        let (start, mut token) =
+            parse_token(nodes.iter().map(|node| node.id), &req.starting_token)?;
+
+        let mut entries = Vec::new();
+        let mut next_token = String::new();
+        for node in &nodes[start..] {
+            // Stop once the page is full, continuing with this node on the next one
+            let max_entries = remaining_entries(req.max_entries, entries.len())?;
+            if req.max_entries != 0 && max_entries == 0 {
+                next_token = encode_id(node.id, "");
+                break;
+            }
+
+            let page = node
+                .controller
+                .list_volumes(node.request(ListVolumesRequest {
+                    max_entries,
+                    starting_token: std::mem::take(&mut token),
+                }))
+                .await?
+                .into_inner();
+
+            entries.extend(page.entries.into_iter().map(|mut entry| {
+                entry.volume = entry.volume.map(|volume| node.encode_volume(volume));
+                entry
+            }));
+
+            if !page.next_token.is_empty() {
+                next_token = encode_id(node.id, &page.next_token);
+                break;
+            }
+        }
+
+        Ok(Response::new(ListVolumesResponse {
+            entries,
+            next_token,
+        }))
+    }
+
+    async fn get_capacity(
+        &self,
+        request: Request<GetCapacityRequest>,
+    ) -> Result<Response<GetCapacityResponse>, Status> {
+        let req = request.into_inner();
+
+        // Sum up the capacity of all nodes, which report none if they are not
+        //  accessible from the requested topology
+        let mut reply = GetCapacityResponse::default();
+        let mut answered = false;
+        let mut rejection = None;
+        for node in &self.nodes {
+            let capacity = node
+                .controller
+                .get_capacity(node.request(req.clone()))
+                .await;
+
+            // Skip nodes which are unreachable, or do not have the volume group
+            let capacity = match capacity {
+                Ok(capacity) => capacity.into_inner(),
+                Err(status) => {
+                    log::warn!(
+                        "could not get capacity of node `{}`: {}",
+                        node.id,
+                        status.message()
+                    );
+
+                    if status.code() == Code::InvalidArgument {
+                        rejection = rejection.or(Some(status));
+                    }
+                    continue;
+                }
+            };
+
+            answered = true;
+            reply.available_capacity += capacity.available_capacity;
+            reply.maximum_volume_size = reply.maximum_volume_size.max(capacity.maximum_volume_size);
+            reply.minimum_volume_size = reply.minimum_volume_size.or(capacity.minimum_volume_size);
+        }
+
+        // Only fail if none of the nodes have the requested volume group
+        match rejection {
+            Some(status) if !answered => Err(status),
+            _ => Ok(Response::new(reply)),
+        }
+    }
+
+    async fn controller_get_capabilities(
+        &self,
+        request: Request<ControllerGetCapabilitiesRequest>,
+    ) -> Result<Response<ControllerGetCapabilitiesResponse>, Status> {
+        // All nodes are handled by the same kind of controller
+        let node = &self.nodes[0];
+        node.controller
+            .controller_get_capabilities(node.request(request.into_inner()))
+            .await
+    }
+
+    async fn create_volume(
+        &self,
+        request: Request<CreateVolumeRequest>,
+    ) -> Result<Response<CreateVolumeResponse>, Status> {
+        let mut req = request.into_inner();
+
+        log::info!("got create volume request: {:?}", req.redacted());
+
+        if req.name.is_empty() {
+            return Err(Status::invalid_argument("missing volume name"));
+        }
+
+        // Copies have to be made on the node holding their source
+        let source_node = match req
+            .volume_content_source
+            .as_mut()
+            .and_then(|source| source.r#type.as_mut())
+        {
+            None => None,
+            Some(ContentType::Volume(source)) => {
+                let (node, id) = self
+                    .existing_owner(Resource::Volume, &source.volume_id)
+                    .await?;
+                source.volume_id = id;
+
+                Some(node)
+            }
+            Some(ContentType::Snapshot(source)) => {
+                let (node, id) = self
+                    .existing_owner(Resource::Snapshot, &source.snapshot_id)
+                    .await?;
+                source.snapshot_id = id;
+
+                Some(node)
+            }
+        };
+
+        // Retries have to end up on the node that the volume was created on
+        let candidates = match (self.find_volume(&req.name).await?, source_node) {
+            (Some(node), _) | (None, Some(node)) => vec![node],
+            (None, None) => self.place(&req).await?,
+        };
+
+        let mut rejection = None;
+        for node in candidates {
+            match node
+                .controller
+                .create_volume(node.request(req.clone()))
+                .await
+            {
+                Ok(response) => {
+                    let mut response = response.into_inner();
+                    response.volume = response.volume.map(|volume| node.encode_volume(volume));
+
+                    return Ok(Response::new(response));
+                }
+
+                // Fall back to the next best node only if this one turned out to be full,
+                //  in which case nothing was created. Other failures (e.g. the node becoming
+                //  unreachable) may have left the volume behind, so the retry has to go to
+                //  the same node.
+                Err(status) if status.code() == Code::ResourceExhausted => {
+                    log::warn!(
+                        "could not create volume `{}` on node `{}`: {}",
+                        req.name,
+                        node.id,
+                        status.message()
+                    );
+
+                    rejection = Some(status);
+                }
+                Err(status) => return Err(status),
+            }
+        }
+
+        Err(rejection.unwrap_or_else(|| Status::resource_exhausted("no node can hold the volume")))
+    }
+
+    async fn delete_volume(
+        &self,
+        request: Request<DeleteVolumeRequest>,
+    ) -> Result<Response<DeleteVolumeResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        match self.owner(Resource::Volume, &req.volume_id).await? {
+            Some((node, volume_id)) => {
+                node.controller
+                    .delete_volume(node.request(DeleteVolumeRequest { volume_id, ..req }))
+                    .await
+            }
+            None => {
+                log::warn!(
+                    "attempted to delete non-existent volume {}, ignoring...",
+                    req.volume_id
+                );
+
+                Ok(Response::new(DeleteVolumeResponse {}))
+            }
+        }
+    }
+
+    async fn validate_volume_capabilities(
+        &self,
+        request: Request<ValidateVolumeCapabilitiesRequest>,
+    ) -> Result<Response<ValidateVolumeCapabilitiesResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        let (node, volume_id) = self
+            .existing_owner(Resource::Volume, &req.volume_id)
+            .await?;
+        node.controller
+            .validate_volume_capabilities(
+                node.request(ValidateVolumeCapabilitiesRequest { volume_id, ..req }),
+            )
+            .await
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CreateSnapshotResponse>, Status> {
+        let req = request.into_inner();
+
+        log::info!("got create snapshot request: {:?}", req.redacted());
+
+        if req.source_volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `source_volume_id`",
+            ));
+        }
+
+        // Snapshots live on the node of their source volume
+        let (node, source_volume_id) = self
+            .existing_owner(Resource::Volume, &req.source_volume_id)
+            .await?;
+        let mut response = node
+            .controller
+            .create_snapshot(node.request(CreateSnapshotRequest {
+                source_volume_id,
+                ..req
+            }))
+            .await?
+            .into_inner();
+        response.snapshot = response
+            .snapshot
+            .map(|snapshot| node.encode_snapshot(snapshot));
+
+        Ok(Response::new(response))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<DeleteSnapshotResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.snapshot_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `snapshot_id`",
+            ));
+        }
+
+        match self.owner(Resource::Snapshot, &req.snapshot_id).await? {
+            Some((node, snapshot_id)) => {
+                node.controller
+                    .delete_snapshot(node.request(DeleteSnapshotRequest { snapshot_id, ..req }))
+                    .await
+            }
+            None => {
+                log::warn!(
+                    "attempted to delete non-existent snapshot {}, ignoring...",
+                    req.snapshot_id
+                );
+
+                Ok(Response::new(DeleteSnapshotResponse {}))
+            }
+        }
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let mut req = request.into_inner();
+
+        log::info!("got list snapshots request with: {:?}", req.redacted());
+
+        // Snapshots filtered by ID (or by their source volume) live on a single node
+        let nodes: Vec<&ClusterNode> =
+            if req.snapshot_id.is_empty() && req.source_volume_id.is_empty() {
+                self.nodes.iter().collect()
+            } else {
+                let owner = if !req.snapshot_id.is_empty() {
+                    self.owner(Resource::Snapshot, &req.snapshot_id).await?
+                } else {
+                    self.owner(Resource::Volume, &req.source_volume_id).await?
+                };
+
+                match owner {
+                    Some((node, _)) => {
+                        req.snapshot_id = strip_node(&req.snapshot_id)?;
+                        req.source_volume_id = strip_node(&req.source_volume_id)?;
+
+                        vec![node]
+                    }
+                    None => return Ok(Response::new(ListSnapshotsResponse::default())),
+                }
+            };
+
+        // Nodes are listed one after the other, resuming from the node in the token
+        let (start, mut token) =
+            parse_token(nodes.iter().map(|node| node.id), &req.starting_token)?;
+
+        let mut entries = Vec::new();
+        let mut next_token = String::new();
+        for node in &nodes[start..] {
+            // Stop once the page is full, continuing with this node on the next one
+            let max_entries = remaining_entries(req.max_entries, entries.len())?;
+            if req.max_entries != 0 && max_entries == 0 {
+                next_token = encode_id(node.id, "");
+                break;
+            }
+
+            let page = node
+                .controller
+                .list_snapshots(node.request(ListSnapshotsRequest {
+                    max_entries,
+                    starting_token: std::mem::take(&mut token),
+                    ..req.clone()
+                }))
+                .await?
+                .into_inner();
+
+            entries.extend(page.entries.into_iter().map(|mut entry| {
+                entry.snapshot = entry
+                    .snapshot
+                    .map(|snapshot| node.encode_snapshot(snapshot));
+                entry
+            }));
+
+            if !page.next_token.is_empty() {
+                next_token = encode_id(node.id, &page.next_token);
+                break;
+            }
+        }
+
+        Ok(Response::new(ListSnapshotsResponse {
+            entries,
+            next_token,
+        }))
+    }
+
+    async fn controller_expand_volume(
+        &self,
+        request: Request<ControllerExpandVolumeRequest>,
+    ) -> Result<Response<ControllerExpandVolumeResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        let (node, volume_id) = self
+            .existing_owner(Resource::Volume, &req.volume_id)
+            .await?;
+        node.controller
+            .controller_expand_volume(
+                node.request(ControllerExpandVolumeRequest { volume_id, ..req }),
+            )
+            .await
+    }
+
+    // --- Unimplemented below ---
+
+    async fn controller_publish_volume(
+        &self,
+        _request: Request<ControllerPublishVolumeRequest>,
+    ) -> Result<Response<ControllerPublishVolumeResponse>, Status> {
+        Err(Status::unimplemented("not implemented"))
+    }
+
+    async fn controller_unpublish_volume(
+        &self,
+        _request: Request<ControllerUnpublishVolumeRequest>,
+    ) -> Result<Response<ControllerUnpublishVolumeResponse>, Status> {
+        Err(Status::unimplemented("not implemented"))
+    }
+
+    async fn controller_get_volume(
+        &self,
+        request: Request<ControllerGetVolumeRequest>,
+    ) -> Result<Response<ControllerGetVolumeResponse>, Status> {
+        let req = request.into_inner();
+
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        let (node, volume_id) = self
+            .existing_owner(Resource::Volume, &req.volume_id)
+            .await?;
+        let mut response = node
+            .controller
+            .controller_get_volume(node.request(ControllerGetVolumeRequest { volume_id }))
+            .await?
+            .into_inner();
+        response.volume = response.volume.map(|volume| node.encode_volume(volume));
+
+        Ok(Response::new(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NODE: &str = "1b4e28ba-2fa1-11d2-883f-0016d3cca427";
+    const OTHER_NODE: &str = "6fa459ea-ee8a-3ca4-894e-db77e160355e";
+
+    fn node_ids() -> Vec<Uuid> {
+        vec![NODE.parse().unwrap(), OTHER_NODE.parse().unwrap()]
+    }
+
+    #[test]
+    fn decode_qualified_id() {
+        let id = format!("{}:some-uuid", NODE);
+
+        assert_eq!(
+            decode_id(&id).unwrap(),
+            (Some(NODE.parse().unwrap()), "some-uuid")
+        );
+    }
+
+    #[test]
+    fn decode_bare_id() {
+        assert_eq!(decode_id("some-uuid").unwrap(), (None, "some-uuid"));
+    }
+
+    #[test]
+    fn decode_invalid_node() {
+        let status = decode_id("not-a-node:some-uuid").unwrap_err();
+
+        assert_eq!(status.code(), Code::NotFound);
+    }
+
+    #[test]
+    fn parse_empty_token() {
+        assert_eq!(parse_token(node_ids(), "").unwrap(), (0, String::new()));
+    }
+
+    #[test]
+    fn parse_token_of_node() {
+        let token = format!("{}:vg/name", OTHER_NODE);
+
+        assert_eq!(
+            parse_token(node_ids(), &token).unwrap(),
+            (1, "vg/name".to_string())
+        );
+    }
+
+    #[test]
+    fn parse_token_of_unknown_node() {
+        let token = format!("{}:vg/name", Uuid::nil());
+
+        assert_eq!(
+            parse_token(node_ids(), &token).unwrap_err().code(),
+            Code::Aborted
+        );
+        assert_eq!(
+            parse_token(node_ids(), "garbage").unwrap_err().code(),
+            Code::Aborted
+        );
+    }
+
+    #[test]
+    fn only_loopback_endpoints_skip_tls() {
+        for (endpoint, loopback) in [
+            ("http://127.0.0.1:50051", true),
+            ("http://127.1.2.3:50051", true),
+            ("http://[::1]:50051", true),
+            ("http://localhost:50051", true),
+            ("http://10.0.0.1:50051", false),
+            ("http://[fd00::1]:50051", false),
+            ("http://node-a:50051", false),
+            ("http://127.0.0.1.example.com:50051", false),
+        ] {
+            assert_eq!(
+                is_loopback(&endpoint.parse().unwrap()),
+                loopback,
+                "{}",
+                endpoint
+            );
+        }
+    }
+
+    #[test]
+    fn remaining_entries_of_page() {
+        assert_eq!(remaining_entries(0, 5).unwrap(), 0);
+        assert_eq!(remaining_entries(10, 4).unwrap(), 6);
+        assert_eq!(
+            remaining_entries(-1, 0).unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+}
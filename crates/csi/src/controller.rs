@@ -1,47 +1,97 @@
-use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
-    hash::{Hash, Hasher},
-    num::TryFromIntError,
-};
+use std::{collections::HashMap, num::TryFromIntError};
 
-use tonic::transport::Channel;
+use common::round_to_extents;
+use sha2::{Digest, Sha256};
 use tonic::Code;
 use tonic::{Request, Response, Status};
-use uuid::Uuid;
+use volumed::filesystem::Filesystem;
+use volumed::server::{ENCRYPTED_TAG, FS_TYPE_TAG, SIGNATURE_METADATA_KEY};
 use volumed::spec::get_lv_request::Identifier;
-use volumed::spec::volume_service_client::VolumeServiceClient;
-use volumed::spec::DeleteLvRequest;
-use volumed::spec::{CreateLvRequest, Empty, FormatLvRequest, GetLvRequest, LogicalVolume};
+use volumed::spec::get_snapshot_request::Identifier as SnapshotIdentifier;
+use volumed::spec::{
+    format_options::Reflink, AdoptLvRequest, CreateLvRequest, EncryptLvRequest, FormatLvRequest,
+    FormatOptions, GetFreeBytesRequest, GetLvListRequest, GetLvRequest, LogicalVolume,
+};
+use volumed::spec::{
+    CreateSnapshotRequest as CreateLvSnapshotRequest,
+    DeleteSnapshotRequest as DeleteLvSnapshotRequest, Snapshot as LvSnapshot,
+};
+use volumed::spec::{DeleteLvRequest, GetSnapshotListRequest, GetSnapshotRequest, ResizeLvRequest};
 
 use crate::csi::v1_7_0::controller_server::ControllerServer;
 use crate::csi::v1_7_0::validate_volume_capabilities_response::Confirmed;
-use crate::csi::v1_7_0::volume_capability::{AccessMode, AccessType, BlockVolume, MountVolume};
+use crate::csi::v1_7_0::volume_capability::AccessType;
+use crate::csi::v1_7_0::volume_content_source::{
+    SnapshotSource, Type as ContentType, VolumeSource,
+};
 use crate::csi::v1_7_0::VolumeCapability;
 use crate::csi::v1_7_0::{
-    controller_server::Controller, list_volumes_response::Entry as VolumeEntry,
-    volume_capability::access_mode::Mode, ControllerExpandVolumeRequest,
-    ControllerExpandVolumeResponse, ControllerGetCapabilitiesRequest,
-    ControllerGetCapabilitiesResponse, ControllerGetVolumeRequest, ControllerGetVolumeResponse,
-    ControllerPublishVolumeRequest, ControllerPublishVolumeResponse,
-    ControllerUnpublishVolumeRequest, ControllerUnpublishVolumeResponse, CreateSnapshotRequest,
-    CreateSnapshotResponse, CreateVolumeRequest, CreateVolumeResponse, DeleteSnapshotRequest,
-    DeleteSnapshotResponse, DeleteVolumeRequest, DeleteVolumeResponse, GetCapacityRequest,
-    GetCapacityResponse, ListSnapshotsRequest, ListSnapshotsResponse, ListVolumesRequest,
-    ListVolumesResponse, Topology, ValidateVolumeCapabilitiesRequest,
-    ValidateVolumeCapabilitiesResponse, Volume,
+    controller_get_volume_response::VolumeStatus as GetVolumeStatus, controller_server::Controller,
+    list_snapshots_response::Entry as SnapshotEntry, list_volumes_response::Entry as VolumeEntry,
+    list_volumes_response::VolumeStatus as ListVolumeStatus, volume_capability::access_mode::Mode,
+    ControllerExpandVolumeRequest, ControllerExpandVolumeResponse,
+    ControllerGetCapabilitiesRequest, ControllerGetCapabilitiesResponse,
+    ControllerGetVolumeRequest, ControllerGetVolumeResponse, ControllerPublishVolumeRequest,
+    ControllerPublishVolumeResponse, ControllerUnpublishVolumeRequest,
+    ControllerUnpublishVolumeResponse, CreateSnapshotRequest, CreateSnapshotResponse,
+    CreateVolumeRequest, CreateVolumeResponse, DeleteSnapshotRequest, DeleteSnapshotResponse,
+    DeleteVolumeRequest, DeleteVolumeResponse, GetCapacityRequest, GetCapacityResponse,
+    ListSnapshotsRequest, ListSnapshotsResponse, ListVolumesRequest, ListVolumesResponse, Snapshot,
+    Topology, ValidateVolumeCapabilitiesRequest, ValidateVolumeCapabilitiesResponse, Volume,
+    VolumeCondition, VolumeContentSource,
 };
-use crate::MIN_VOLUME_SIZE_BYTES;
+use crate::{
+    topology, Redact, DEFAULT_FS_TYPE, ENCRYPTED_CONTEXT_KEY, FS_TYPE_CONTEXT_KEY,
+    GROW_FS_CONTEXT_KEY, MIN_VOLUME_SIZE_BYTES, PASSPHRASE_SECRET_KEY, SUPPORTED_FS_TYPES,
+};
+
+use volumed::auth::Client;
+
+/// Prefix of the names of LVs backing CSI volumes
+const VOLUME_NAME_PREFIX: &str = "csi-vol-";
+
+/// Prefix of the names of LVs backing CSI snapshots
+const SNAPSHOT_NAME_PREFIX: &str = "csi-snap-";
+
+/// Prefix of the tag holding the (hex-encoded) CSI name of an LV
+const NAME_TAG_PREFIX: &str = "csi-name=";
+
+/// Prefix of the tag holding the raw CSI name of an LV created by an older release
+const LEGACY_NAME_TAG_PREFIX: &str = "name=";
+
+/// Prefix of the tag holding the ID of the volume that an LV was cloned from
+const SOURCE_VOLUME_TAG_PREFIX: &str = "source-volume=";
+
+/// Prefix of the tag holding the ID of the snapshot that an LV was restored from
+const SOURCE_SNAPSHOT_TAG_PREFIX: &str = "source-snapshot=";
+
+/// Prefix of the tag holding the options an LV was formatted with (see [format_options_tag])
+const FORMAT_OPTIONS_TAG_PREFIX: &str = "format-options=";
 
-type Client = VolumeServiceClient<Channel>;
+/// StorageClass parameter selecting the volume group to allocate from
+pub(crate) const VOLUME_GROUP_PARAMETER: &str = "volumeGroup";
+
+/// StorageClass parameter for the block size (in bytes) of the filesystem
+const BLOCK_SIZE_PARAMETER: &str = "blockSize";
+
+/// StorageClass parameter for the inode size (in bytes) of the filesystem
+const INODE_SIZE_PARAMETER: &str = "inodeSize";
+
+/// StorageClass parameter for enabling / disabling reflinks in the filesystem
+const REFLINK_PARAMETER: &str = "reflink";
+
+/// StorageClass parameter for encrypting volumes with LUKS
+const ENCRYPTED_PARAMETER: &str = "encrypted";
 
 #[derive(Clone, Debug)]
 pub struct RLVMController {
-    node_id: Uuid,
+    /// Topology of the current node, which all of its volumes are accessible from
+    topology: Topology,
 }
 
 impl RLVMController {
-    pub fn new(node_id: Uuid) -> Self {
-        Self { node_id }
+    pub fn new(topology: Topology) -> Self {
+        Self { topology }
     }
 
     pub fn into_service(self) -> ControllerServer<Self> {
@@ -49,9 +99,13 @@ impl RLVMController {
     }
 
     fn get_host_topology(&self) -> Topology {
-        Topology {
-            segments: HashMap::from([("host".into(), self.node_id.to_string())]),
-        }
+        self.topology.clone()
+    }
+
+    /// Whether volumes of the current node are accessible from the (possibly partial)
+    /// topology, i.e. all of its segments match those of the current node
+    fn is_accessible_from(&self, topology: &Topology) -> bool {
+        topology::matches(&self.topology, topology)
     }
 
     /// Returns the accessible topology of the current node
@@ -61,17 +115,40 @@ impl RLVMController {
 
     /// Convert a [LogicalVolume] into a [Volume]
     fn process_volume(&self, lv: LogicalVolume) -> Volume {
+        // Attach some LV info for context
+        let mut volume_context = HashMap::from([
+            ("name".into(), lv.name.to_string()),
+            (VOLUME_GROUP_PARAMETER.into(), lv.volume_group.to_string()),
+        ]);
+        if is_encrypted(&lv) {
+            volume_context.insert(ENCRYPTED_CONTEXT_KEY.into(), "true".into());
+        }
+
         Volume {
             capacity_bytes: lv.capacity_bytes as i64,
+            content_source: content_source(&lv),
             volume_id: lv.uuid,
-            content_source: None,
-
-            // Attach some LV info for context
-            volume_context: HashMap::from([("name".into(), lv.name.to_string())]),
+            volume_context,
 
             accessible_topology: self.get_access_topologies(),
         }
     }
+
+    /// Convert a volumed snapshot into a CSI [Snapshot]
+    fn process_snapshot(&self, snapshot: LvSnapshot) -> Snapshot {
+        Snapshot {
+            size_bytes: snapshot.capacity_bytes as i64,
+            snapshot_id: snapshot.uuid,
+            source_volume_id: snapshot.source_uuid,
+            creation_time: Some(prost_types::Timestamp {
+                seconds: snapshot.creation_time,
+                nanos: 0,
+            }),
+
+            // LVM snapshots are usable as soon as they are created
+            ready_to_use: true,
+        }
+    }
 }
 
 /// Construct the needed structure for a controller capability.
@@ -111,62 +188,48 @@ impl Controller for RLVMController {
         log::info!("got list volume request with: {:?}", req);
 
         // Validate the inputs
-        let max_entries: usize = req.max_entries.try_into().map_err(|err: TryFromIntError| {
+        let max_entries: u32 = req.max_entries.try_into().map_err(|err: TryFromIntError| {
             Status::invalid_argument(format!(
                 "`max_entries` must be a valid positive integer: {}",
                 err.to_string()
             ))
         })?;
 
-        let start = if !req.starting_token.is_empty() {
-            req.starting_token.parse::<usize>().map_err(|err| {
-                Status::aborted(format!(
-                    "`starting_token` must be a valid positive integer: {}",
-                    err.to_string()
-                ))
-            })?
-        } else {
-            0
-        };
-
-        // Get the LVs from the volumed service
-        let lvs: Vec<VolumeEntry> = client
-            .get_lv_list(Request::new(Empty {}))
+        // Get the requested page of LVs from the volumed service. The token is passed
+        //  through as-is, and volumed takes care of rejecting stale ones with ABORTED.
+        let page = client
+            .get_lv_list(Request::new(GetLvListRequest {
+                max_entries,
+                starting_token: req.starting_token,
+                volume_group: String::new(),
+            }))
             .await
-            .map_err(|err| {
-                Status::internal(format!(
+            .map_err(|err| match err.code() {
+                Code::Aborted => err,
+                _ => Status::internal(format!(
                     "could not get_lv_list from volumed: {}",
                     err.to_string()
-                ))
+                )),
             })?
-            .into_inner()
+            .into_inner();
+
+        let entries = page
             .volumes
             .into_iter()
             .map(|lv| VolumeEntry {
+                status: Some(ListVolumeStatus {
+                    published_node_ids: vec![],
+                    volume_condition: Some(volume_condition(&lv)),
+                }),
                 volume: Some(self.process_volume(lv)),
-
-                // TODO: Qualify the status of the volume using LV attrs
-                status: None,
-            })
-            .take(if max_entries == 0 {
-                usize::MAX
-            } else {
-                max_entries
             })
             .collect();
 
-        let last_index = start + max_entries;
-        let length = lvs.len();
         Ok(Response::new(ListVolumesResponse {
-            entries: lvs,
+            entries,
 
-            // Only set the `next_token` field if both `max_start` was provided and
-            //  if there are more volumes left
-            next_token: if last_index < length {
-                last_index.to_string()
-            } else {
-                String::new()
-            },
+            // Only set if there are more volumes left
+            next_token: page.next_token,
         }))
     }
 
@@ -200,26 +263,35 @@ impl Controller for RLVMController {
         }
 
         // Short out if we are asking the capacity of a host other than the current
-        if Some(self.get_host_topology()) == req.accessible_topology {
-            return Ok(Response::new(GetCapacityResponse::default()));
+        if let Some(topology) = &req.accessible_topology {
+            if !self.is_accessible_from(topology) {
+                return Ok(Response::new(GetCapacityResponse::default()));
+            }
         }
 
-        // Call out to volumed for the capacity
+        // Call out to volumed for the capacity of the selected volume group, passing
+        //  through INVALID_ARGUMENT for volume groups it does not manage
         let capacity = client
-            .get_free_bytes(Empty {})
+            .get_free_bytes(GetFreeBytesRequest {
+                volume_group: req
+                    .parameters
+                    .get(VOLUME_GROUP_PARAMETER)
+                    .cloned()
+                    .unwrap_or_default(),
+            })
             .await
-            .map_err(|err| {
-                Status::internal(format!(
+            .map_err(|err| match err.code() {
+                Code::InvalidArgument => err,
+                _ => Status::internal(format!(
                     "could not get_free_bytes from volumed: {}",
                     err.to_string()
-                ))
+                )),
             })?
-            .into_inner()
-            .bytes_free;
+            .into_inner();
 
         let reply = GetCapacityResponse {
-            available_capacity: capacity as i64,
-            maximum_volume_size: None,
+            available_capacity: capacity.bytes_free as i64,
+            maximum_volume_size: Some(capacity.max_volume_bytes as i64),
             minimum_volume_size: Some(MIN_VOLUME_SIZE_BYTES as i64),
         };
 
@@ -235,6 +307,12 @@ impl Controller for RLVMController {
                 controller_capability!(ListVolumes),
                 controller_capability!(CreateDeleteVolume),
                 controller_capability!(GetCapacity),
+                controller_capability!(CreateDeleteSnapshot),
+                controller_capability!(ListSnapshots),
+                controller_capability!(ExpandVolume),
+                controller_capability!(CloneVolume),
+                controller_capability!(GetVolume),
+                controller_capability!(VolumeCondition),
             ],
         };
 
@@ -248,7 +326,7 @@ impl Controller for RLVMController {
         let mut client = request.extensions().get::<Client>().unwrap().clone();
         let req = request.into_inner();
 
-        log::info!("got create volume request: {:?}", req);
+        log::info!("got create volume request: {:?}", req.redacted());
 
         // Validate args
         if req.name.is_empty() {
@@ -258,9 +336,92 @@ impl Controller for RLVMController {
             return Err(Status::invalid_argument("missing volume capabilities"));
         }
 
-        // Parse the capacity bytes
-        // TODO: Do something with the limit
-        let (capacity, _limit) = match req.capacity_range {
+        // Volumes are only ever accessible from the current node, so make sure that it
+        //  satisfies the requested topology
+        if let Some(requirements) = &req.accessibility_requirements {
+            topology::check_requirements(&self.topology, requirements)?;
+        }
+
+        // Figure out how the volume should be formatted, if at all
+        let fs_type = requested_fs_type(&req.volume_capabilities)?;
+        let format_options = parse_format_options(&req.parameters, fs_type.as_deref())?;
+        let encrypted = requested_encryption(&req.parameters)?;
+        let volume_group = req
+            .parameters
+            .get(VOLUME_GROUP_PARAMETER)
+            .cloned()
+            .unwrap_or_default();
+
+        // Resolve the volume or snapshot to copy the contents from, if any
+        let source = match req
+            .volume_content_source
+            .as_ref()
+            .and_then(|source| source.r#type.as_ref())
+        {
+            None => None,
+            Some(ContentType::Volume(VolumeSource { volume_id })) => {
+                let lv = client
+                    .get_logical_volume(Request::new(GetLvRequest {
+                        identifier: Some(Identifier::Uuid(volume_id.clone())),
+                        volume_group: String::new(),
+                    }))
+                    .await
+                    .map_err(|err| Status::not_found(err.message().to_string()))?
+                    .into_inner();
+
+                Some(CopySource {
+                    tag: format!("{}{}", SOURCE_VOLUME_TAG_PREFIX, volume_id),
+                    encrypted: is_encrypted(&lv),
+                    name: lv.name,
+                    volume_group: lv.volume_group,
+                    capacity_bytes: lv.capacity_bytes,
+                })
+            }
+            Some(ContentType::Snapshot(SnapshotSource { snapshot_id })) => {
+                let snapshot = client
+                    .get_snapshot(Request::new(GetSnapshotRequest {
+                        identifier: Some(SnapshotIdentifier::Uuid(snapshot_id.clone())),
+                        volume_group: String::new(),
+                    }))
+                    .await
+                    .map_err(|err| Status::not_found(err.message().to_string()))?
+                    .into_inner();
+
+                Some(CopySource {
+                    tag: format!("{}{}", SOURCE_SNAPSHOT_TAG_PREFIX, snapshot_id),
+                    encrypted: snapshot.tags.iter().any(|tag| tag == ENCRYPTED_TAG),
+                    name: snapshot.name,
+                    volume_group: snapshot.volume_group,
+                    capacity_bytes: snapshot.capacity_bytes,
+                })
+            }
+        };
+
+        // Copies keep the encryption (and passphrase) of their source, so they cannot be
+        //  encrypted unless their source already is
+        if let Some(source) = source
+            .as_ref()
+            .filter(|source| encrypted && !source.encrypted)
+        {
+            return Err(Status::invalid_argument(format!(
+                "cannot create an encrypted volume from unencrypted source `{}`",
+                source.name
+            )));
+        }
+
+        // ...so only fresh volumes need a passphrase
+        let passphrase = match (encrypted, &source) {
+            (true, None) => Some(req.secrets.get(PASSPHRASE_SECRET_KEY).ok_or_else(|| {
+                Status::invalid_argument(format!(
+                    "encrypted volumes require the `{}` secret",
+                    PASSPHRASE_SECRET_KEY
+                ))
+            })?),
+            _ => None,
+        };
+
+        // Parse the capacity range, where zero means unset
+        let (required, limit) = match &req.capacity_range {
             Some(cap) => cap
                 .required_bytes
                 .try_into()
@@ -272,82 +433,232 @@ impl Controller for RLVMController {
 
                     Ok((size, limit))
                 })?,
-            None => (MIN_VOLUME_SIZE_BYTES, 0),
+            None => (0, 0),
+        };
+        let mut capacity = if required == 0 {
+            MIN_VOLUME_SIZE_BYTES
+        } else {
+            required
         };
 
-        // Call out to volumed for the capacity
-        let total_capacity = client
-            .get_free_bytes(Empty {})
-            .await
-            .map_err(|err| {
-                Status::internal(format!(
-                    "could not get_free_bytes from volumed: {}",
-                    err.to_string()
-                ))
-            })?
-            .into_inner()
-            .bytes_free;
+        // Copies must be able to hold all of the source, and default to its size
+        if let Some(source) = &source {
+            let source_capacity = source.capacity_bytes as usize;
+            if required == 0 {
+                capacity = capacity.max(source_capacity);
+            } else if capacity < source_capacity {
+                return Err(Status::out_of_range(format!(
+                    "cannot create a volume smaller than its source `{}`: {} < {}",
+                    source.name, capacity, source_capacity,
+                )));
+            }
+        }
 
-        // Short out if the capacity is outside the allowable range
+        // Short out if the capacity is outside the allowable range. Whether it fits is
+        //  up to volumed, which admits allocations atomically.
         if capacity < MIN_VOLUME_SIZE_BYTES {
             return Err(Status::out_of_range(format!(
                 "cannot create a volume smaller than the smallest allowed size: {} < {}",
                 capacity, MIN_VOLUME_SIZE_BYTES,
             )));
         }
-        if capacity > (total_capacity as usize) {
+
+        // LVM allocates whole extents, so the volume ends up larger than requested
+        //  unless the size is a multiple of the extent size
+        let extent_size = extent_size(&mut client, &volume_group).await?;
+        let capacity = round_to_extents(capacity as u64, extent_size) as usize;
+        if limit != 0 && capacity > limit {
             return Err(Status::out_of_range(format!(
-                "cannot create volume larger than the space available: {} > {}",
-                capacity, total_capacity,
+                "cannot create a volume within the limit: {} bytes (rounded up to extents of {} bytes) > {}",
+                capacity, extent_size, limit,
             )));
         }
 
         // Short out if we have already created the volume before
-        let safe_name = hash_resource(req.name.clone());
-        let volume = client
-            .get_logical_volume(Request::new(GetLvRequest {
-                identifier: Some(Identifier::Name(safe_name.clone())),
-            }))
-            .await;
+        let safe_name = hash_resource(VOLUME_NAME_PREFIX, &req.name);
+        let volume = find_volume(&mut client, &req.name).await?;
 
         let volume = match volume {
-            Ok(old) => {
-                let old = old.into_inner();
-
-                // Fail if the duplicate request has a different size
-                if old.capacity_bytes as usize != capacity {
+            Some(old) => {
+                // Fail if the duplicate request has an incompatible size
+                let old_capacity = old.capacity_bytes as usize;
+                if old_capacity < capacity || (limit != 0 && old_capacity > limit) {
                     return Err(Status::already_exists(format!(
-                        "attempting to create an existing volume with different capacities: found {:?}, {} bytes requested",
+                        "attempting to create an existing volume with different capacities: found {:?}, {} bytes (limit {}) requested",
                         old,
                         capacity,
+                        limit,
+                    )));
+                }
+
+                // ...or a different source
+                let old_source = old.tags.iter().find(|tag| {
+                    tag.starts_with(SOURCE_VOLUME_TAG_PREFIX)
+                        || tag.starts_with(SOURCE_SNAPSHOT_TAG_PREFIX)
+                });
+                if old_source != source.as_ref().map(|source| &source.tag) {
+                    return Err(Status::already_exists(format!(
+                        "attempting to create an existing volume with a different content source: found {:?}, {:?} requested",
+                        old,
+                        req.volume_content_source,
+                    )));
+                }
+
+                // ...or a different encryption. An earlier attempt may have failed before
+                //  encrypting the volume, which is finished below.
+                let expected = encrypted || matches!(&source, Some(source) if source.encrypted);
+                if is_encrypted(&old) && !expected {
+                    return Err(Status::already_exists(format!(
+                        "attempting to create an existing volume with a different encryption: found {:?}, encrypted={} requested",
+                        old,
+                        encrypted,
+                    )));
+                }
+
+                // ...or different parameters
+                if let Some(mismatch) =
+                    mismatched_parameters(&old, &volume_group, fs_type.as_deref(), &format_options)
+                {
+                    return Err(Status::already_exists(format!(
+                        "attempting to create an existing volume with different parameters: {}",
+                        mismatch
                     )));
                 }
 
                 old
             }
-            Err(status) => {
-                match status.code() {
-                    // Actually create the volume, if not previously found
-                    Code::NotFound => client
-                        .create_logical_volume(Request::new(CreateLvRequest {
-                            name: safe_name.clone(),
-                            capacity: capacity as u64,
-                            tags: vec![format!("name={}", req.name)],
-                        }))
-                        .await?
-                        .into_inner(),
-                    _ => return Err(status),
+            // Actually create the volume, if not previously found
+            None => {
+                let mut tags = vec![name_tag(&req.name)];
+                tags.extend(source.as_ref().map(|source| source.tag.clone()));
+                if fs_type.is_some() && source.is_none() {
+                    tags.push(format_options_tag(&format_options));
                 }
+
+                client
+                    .create_logical_volume(Request::new(CreateLvRequest {
+                        name: safe_name,
+                        capacity: capacity as u64,
+                        tags,
+                        volume_group,
+                        source_name: source
+                            .as_ref()
+                            .map(|source| source.name.clone())
+                            .unwrap_or_default(),
+                        source_volume_group: source
+                            .as_ref()
+                            .map(|source| source.volume_group.clone())
+                            .unwrap_or_default(),
+                    }))
+                    .await?
+                    .into_inner()
+            }
+        };
+
+        // Set up encryption before anything gets written to the volume. volumed refuses
+        //  to encrypt volumes which already contain data.
+        let volume = match passphrase {
+            Some(passphrase) if !is_encrypted(&volume) => {
+                client
+                    .encrypt_logical_volume(Request::new(EncryptLvRequest {
+                        name: volume.name.clone(),
+                        volume_group: volume.volume_group.clone(),
+                        passphrase: passphrase.clone(),
+                    }))
+                    .await?;
+
+                // Pick up the tags added while encrypting
+                client
+                    .get_logical_volume(Request::new(GetLvRequest {
+                        identifier: Some(Identifier::Uuid(volume.uuid)),
+                        volume_group: volume.volume_group,
+                    }))
+                    .await?
+                    .into_inner()
             }
+            _ => volume,
         };
 
-        // TODO: Only format if we are given a request for an fs volume
-        client
-            .format_logical_volume(Request::new(FormatLvRequest { name: safe_name }))
-            .await?;
+        // Only ever format volumes created for this request (possibly by an earlier,
+        //  failed attempt at it), and never forcefully, so that retries cannot wipe
+        //  data written in the meantime. Raw block volumes and copies are handed over
+        //  as-is.
+        if let (Some(fs_type), None) = (&fs_type, &source) {
+            if volume_fs_type(&volume).is_none() {
+                let formatted = client
+                    .format_logical_volume(Request::new(FormatLvRequest {
+                        name: volume.name.clone(),
+                        force: false,
+                        fs_type: fs_type.clone(),
+                        options: Some(format_options.clone()),
+                        volume_group: volume.volume_group.clone(),
+                        passphrase: passphrase.cloned().unwrap_or_default(),
+                    }))
+                    .await;
+
+                match formatted {
+                    // An earlier attempt got to format the volume, but not to tag it. Only
+                    //  take over what that attempt would have created for this request.
+                    Err(status) if status.code() == Code::AlreadyExists => {
+                        let found = status
+                            .metadata()
+                            .get(SIGNATURE_METADATA_KEY)
+                            .and_then(|value| value.to_str().ok());
+                        if let Some(mismatch) =
+                            mismatched_filesystem(&volume, found, fs_type, &format_options)
+                        {
+                            return Err(Status::already_exists(format!(
+                                "attempting to create an existing volume with a different filesystem: {}",
+                                mismatch
+                            )));
+                        }
+
+                        log::warn!(
+                            "volume `{}` already contains a `{}` filesystem, tagging it instead of formatting it",
+                            req.name,
+                            fs_type
+                        );
+
+                        // Tag the volume like volumed does after formatting it
+                        client
+                            .adopt_logical_volume(Request::new(AdoptLvRequest {
+                                name: volume.name.clone(),
+                                volume_group: volume.volume_group.clone(),
+                                tags: vec![format!("{}={}", FS_TYPE_TAG, fs_type)],
+                            }))
+                            .await?;
+                    }
+                    result => {
+                        result?;
+                    }
+                }
+            }
+        }
+
+        // Copies keep whatever filesystem their source had, which only fills as much of
+        //  the volume as the source did until it is grown on the node
+        let grow_fs =
+            matches!(&source, Some(source) if volume.capacity_bytes > source.capacity_bytes);
+        let fs_type = match source {
+            Some(_) => volume_fs_type(&volume).map(str::to_string),
+            None => fs_type,
+        };
+
+        let mut volume = self.process_volume(volume);
+        if let Some(fs_type) = fs_type {
+            volume
+                .volume_context
+                .insert(FS_TYPE_CONTEXT_KEY.into(), fs_type);
+
+            if grow_fs {
+                volume
+                    .volume_context
+                    .insert(GROW_FS_CONTEXT_KEY.into(), "true".into());
+            }
+        }
 
         Ok(Response::new(CreateVolumeResponse {
-            volume: Some(self.process_volume(volume)),
+            volume: Some(volume),
         }))
     }
 
@@ -368,21 +679,27 @@ impl Controller for RLVMController {
         let lv = client
             .get_logical_volume(Request::new(GetLvRequest {
                 identifier: Some(Identifier::Uuid(req.volume_id.clone())),
+                volume_group: String::new(),
             }))
-            .await
-            .ok();
+            .await;
 
-        // Delete the volume, if it exists
-        if let Some(volume) = lv {
-            let volume = volume.into_inner();
-            client
-                .delete_logical_volume(Request::new(DeleteLvRequest { name: volume.name }))
-                .await?;
-        } else {
-            log::warn!(
+        // Delete the volume, if it exists. Anything else (e.g. a volume which is not
+        //  managed by volumed) must not be mistaken for a successful deletion.
+        match lv {
+            Ok(volume) => {
+                let volume = volume.into_inner();
+                client
+                    .delete_logical_volume(Request::new(DeleteLvRequest {
+                        name: volume.name,
+                        volume_group: volume.volume_group,
+                    }))
+                    .await?;
+            }
+            Err(status) if status.code() == Code::NotFound => log::warn!(
                 "attempted to delete non-existent volume {}, ignoring...",
                 req.volume_id
-            );
+            ),
+            Err(status) => return Err(status),
         }
 
         Ok(Response::new(DeleteVolumeResponse {}))
@@ -411,47 +728,320 @@ impl Controller for RLVMController {
         let lv = client
             .get_logical_volume(Request::new(GetLvRequest {
                 identifier: Some(Identifier::Uuid(req.volume_id)),
+                volume_group: String::new(),
             }))
             .await
             .map_err(|err| Status::not_found(err.to_string()))?
             .into_inner();
 
-        // TODO: We need to check the specific capabilities passed by the CO...
-        let reply = ValidateVolumeCapabilitiesResponse {
-            confirmed: Some(Confirmed {
-                parameters: HashMap::new(),
-                volume_capabilities: vec![
-                    VolumeCapability {
-                        access_mode: Some(AccessMode {
-                            mode: Mode::SingleNodeWriter.into(),
-                        }),
-                        access_type: Some(AccessType::Block(BlockVolume {}).into()),
-                    },
-                    VolumeCapability {
-                        access_mode: Some(AccessMode {
-                            mode: Mode::SingleNodeWriter.into(),
-                        }),
-                        access_type: Some(
-                            AccessType::Mount(MountVolume {
-                                fs_type: "xfs".into(),
-                                mount_flags: vec![],
-
-                                // TODO: No idea what this is
-                                volume_mount_group: "".into(),
-                            })
-                            .into(),
-                        ),
-                    },
-                ],
-
-                volume_context: HashMap::from([("name".into(), lv.name.to_string())]),
-            }),
-            message: "".into(),
+        // Figure out how the volume was provisioned, filesystem volumes are tagged with
+        //  the filesystem they were formatted with
+        let fs_type = volume_fs_type(&lv);
+
+        let reason = req
+            .volume_capabilities
+            .iter()
+            .find_map(|cap| unsupported_capability(cap, fs_type))
+            .or_else(|| {
+                parse_format_options(&req.parameters, fs_type)
+                    .err()
+                    .map(|err| err.message().to_string())
+            })
+            .or_else(|| inconsistent_context(&req.volume_context, &req.parameters, &lv, fs_type));
+
+        let reply = match reason {
+            Some(message) => ValidateVolumeCapabilitiesResponse {
+                confirmed: None,
+                message,
+            },
+            None => ValidateVolumeCapabilitiesResponse {
+                confirmed: Some(Confirmed {
+                    volume_context: req.volume_context,
+                    volume_capabilities: req.volume_capabilities,
+                    parameters: req.parameters,
+                }),
+                message: "".into(),
+            },
         };
 
         Ok(Response::new(reply))
     }
 
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<CreateSnapshotResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let req = request.into_inner();
+
+        log::info!("got create snapshot request: {:?}", req.redacted());
+
+        // Validate args
+        if req.name.is_empty() {
+            return Err(Status::invalid_argument("missing snapshot name"));
+        }
+        if req.source_volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `source_volume_id`",
+            ));
+        }
+
+        // Fetch the volume to snapshot
+        let source = client
+            .get_logical_volume(Request::new(GetLvRequest {
+                identifier: Some(Identifier::Uuid(req.source_volume_id.clone())),
+                volume_group: String::new(),
+            }))
+            .await
+            .map_err(|err| Status::not_found(err.message().to_string()))?
+            .into_inner();
+
+        // Short out if we have already created the snapshot before
+        let snapshot = find_snapshot(&mut client, &req.name).await?;
+
+        let snapshot = match snapshot {
+            Some(old) => {
+                // Fail if the duplicate request is for a different volume
+                if old.source_uuid != source.uuid {
+                    return Err(Status::already_exists(format!(
+                        "attempting to create an existing snapshot of a different volume: found {:?}, source `{}` requested",
+                        old,
+                        source.uuid,
+                    )));
+                }
+
+                old
+            }
+
+            // Actually create the snapshot, if not previously found
+            None => client
+                .create_snapshot(Request::new(CreateLvSnapshotRequest {
+                    name: hash_resource(SNAPSHOT_NAME_PREFIX, &req.name),
+                    source_name: source.name,
+                    tags: vec![name_tag(&req.name)],
+                    volume_group: source.volume_group,
+                }))
+                .await?
+                .into_inner(),
+        };
+
+        Ok(Response::new(CreateSnapshotResponse {
+            snapshot: Some(self.process_snapshot(snapshot)),
+        }))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<DeleteSnapshotResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let req = request.into_inner();
+
+        // Validate args
+        if req.snapshot_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `snapshot_id`",
+            ));
+        }
+
+        let snapshot = client
+            .get_snapshot(Request::new(GetSnapshotRequest {
+                identifier: Some(SnapshotIdentifier::Uuid(req.snapshot_id.clone())),
+                volume_group: String::new(),
+            }))
+            .await;
+
+        // Delete the snapshot, if it exists. Any other error must not be mistaken for
+        //  a successful deletion.
+        match snapshot {
+            Ok(snapshot) => {
+                let snapshot = snapshot.into_inner();
+                client
+                    .delete_snapshot(Request::new(DeleteLvSnapshotRequest {
+                        name: snapshot.name,
+                        volume_group: snapshot.volume_group,
+                    }))
+                    .await?;
+            }
+            Err(status) if status.code() == Code::NotFound => log::warn!(
+                "attempted to delete non-existent snapshot {}, ignoring...",
+                req.snapshot_id
+            ),
+            Err(status) => return Err(status),
+        }
+
+        Ok(Response::new(DeleteSnapshotResponse {}))
+    }
+
+    async fn list_snapshots(
+        &self,
+        request: Request<ListSnapshotsRequest>,
+    ) -> Result<Response<ListSnapshotsResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let req = request.into_inner();
+
+        log::info!("got list snapshots request with: {:?}", req.redacted());
+
+        // Validate the inputs
+        let max_entries: u32 = req.max_entries.try_into().map_err(|err: TryFromIntError| {
+            Status::invalid_argument(format!(
+                "`max_entries` must be a valid positive integer: {}",
+                err
+            ))
+        })?;
+
+        // A specific snapshot is looked up directly, and is simply missing from the
+        //  listing if it does not exist (anymore)
+        if !req.snapshot_id.is_empty() {
+            let snapshot = match client
+                .get_snapshot(Request::new(GetSnapshotRequest {
+                    identifier: Some(SnapshotIdentifier::Uuid(req.snapshot_id.clone())),
+                    volume_group: String::new(),
+                }))
+                .await
+            {
+                Ok(snapshot) => Some(snapshot.into_inner()),
+                Err(status) if status.code() == Code::NotFound => None,
+                Err(status) => {
+                    return Err(Status::internal(format!(
+                        "could not get_snapshot from volumed: {}",
+                        status
+                    )))
+                }
+            };
+
+            let entries = snapshot
+                .filter(|snapshot| {
+                    req.source_volume_id.is_empty() || snapshot.source_uuid == req.source_volume_id
+                })
+                .map(|snapshot| SnapshotEntry {
+                    snapshot: Some(self.process_snapshot(snapshot)),
+                })
+                .into_iter()
+                .collect();
+
+            return Ok(Response::new(ListSnapshotsResponse {
+                entries,
+                next_token: String::new(),
+            }));
+        }
+
+        // Get the requested page of snapshots from the volumed service. The token is
+        //  passed through as-is, and volumed takes care of rejecting stale ones with ABORTED.
+        let page = client
+            .get_snapshot_list(Request::new(GetSnapshotListRequest {
+                max_entries,
+                starting_token: req.starting_token,
+                volume_group: String::new(),
+                source_uuid: req.source_volume_id,
+            }))
+            .await
+            .map_err(|err| match err.code() {
+                Code::Aborted => err,
+                _ => Status::internal(format!("could not get_snapshot_list from volumed: {}", err)),
+            })?
+            .into_inner();
+
+        let entries = page
+            .snapshots
+            .into_iter()
+            .map(|snapshot| SnapshotEntry {
+                snapshot: Some(self.process_snapshot(snapshot)),
+            })
+            .collect();
+
+        Ok(Response::new(ListSnapshotsResponse {
+            entries,
+
+            // Only set if there are more snapshots left
+            next_token: page.next_token,
+        }))
+    }
+
+    async fn controller_expand_volume(
+        &self,
+        request: Request<ControllerExpandVolumeRequest>,
+    ) -> Result<Response<ControllerExpandVolumeResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let req = request.into_inner();
+
+        log::info!("got expand volume request: {:?}", req.redacted());
+
+        // Validate args
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        let (capacity, limit) = match req.capacity_range {
+            Some(cap) => {
+                let size: usize = cap.required_bytes.try_into().map_err(|_| {
+                    Status::invalid_argument("capacity must be a valid unsigned int")
+                })?;
+                let limit: usize = cap.limit_bytes.try_into().map_err(|_| {
+                    Status::invalid_argument("limit bytes must be a valid unsigned int")
+                })?;
+
+                (size, limit)
+            }
+            None => {
+                return Err(Status::invalid_argument(
+                    "missing required field `capacity_range`",
+                ))
+            }
+        };
+
+        // Fetch the volume in question
+        let lv = client
+            .get_logical_volume(Request::new(GetLvRequest {
+                identifier: Some(Identifier::Uuid(req.volume_id.clone())),
+                volume_group: String::new(),
+            }))
+            .await
+            .map_err(|err| Status::not_found(err.message().to_string()))?
+            .into_inner();
+
+        // Short out if the volume is already too large for the requested range
+        if limit != 0 && lv.capacity_bytes as usize > limit {
+            return Err(Status::out_of_range(format!(
+                "volume is already larger than the requested limit: {} > {}",
+                lv.capacity_bytes, limit,
+            )));
+        }
+
+        // ...or would end up too large once rounded to extents
+        let extent_size = extent_size(&mut client, &lv.volume_group).await?;
+        let capacity = round_to_extents(capacity as u64, extent_size) as usize;
+        if limit != 0 && capacity > limit {
+            return Err(Status::out_of_range(format!(
+                "cannot expand the volume within the limit: {} bytes (rounded up to extents of {} bytes) > {}",
+                capacity, extent_size, limit,
+            )));
+        }
+
+        let lv = client
+            .resize_logical_volume(Request::new(ResizeLvRequest {
+                name: lv.name,
+                capacity: capacity as u64,
+                volume_group: lv.volume_group,
+            }))
+            .await?
+            .into_inner();
+
+        // Raw block volumes have no filesystem to grow on the node
+        let is_block = req
+            .volume_capability
+            .and_then(|cap| cap.access_type)
+            .map(|access_type| matches!(access_type, AccessType::Block(_)))
+            .unwrap_or_default();
+
+        Ok(Response::new(ControllerExpandVolumeResponse {
+            capacity_bytes: lv.capacity_bytes as i64,
+            node_expansion_required: !is_block,
+        }))
+    }
+
     // --- Unimplemented below ---
 
     async fn controller_publish_volume(
@@ -468,49 +1058,619 @@ impl Controller for RLVMController {
         Err(Status::unimplemented("not implemented"))
     }
 
-    async fn create_snapshot(
+    async fn controller_get_volume(
         &self,
-        _request: Request<CreateSnapshotRequest>,
-    ) -> Result<Response<CreateSnapshotResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+        request: Request<ControllerGetVolumeRequest>,
+    ) -> Result<Response<ControllerGetVolumeResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let req = request.into_inner();
+
+        log::info!("got get volume request: {:?}", req);
+
+        // Validate args
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `volume_id`",
+            ));
+        }
+
+        let lv = client
+            .get_logical_volume(Request::new(GetLvRequest {
+                identifier: Some(Identifier::Uuid(req.volume_id)),
+                volume_group: String::new(),
+            }))
+            .await?
+            .into_inner();
+
+        Ok(Response::new(ControllerGetVolumeResponse {
+            status: Some(GetVolumeStatus {
+                published_node_ids: vec![],
+                volume_condition: Some(volume_condition(&lv)),
+            }),
+            volume: Some(self.process_volume(lv)),
+        }))
     }
+}
 
-    async fn delete_snapshot(
-        &self,
-        _request: Request<DeleteSnapshotRequest>,
-    ) -> Result<Response<DeleteSnapshotResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+/// Qualify the condition of a volume from the state of its LV
+fn volume_condition(lv: &LogicalVolume) -> VolumeCondition {
+    let message = if lv.partial {
+        "logical volume is missing some of its physical volumes".to_string()
+    } else if !lv.health.is_empty() {
+        format!("logical volume is unhealthy: {}", lv.health)
+    } else if !lv.active {
+        "logical volume is not active".to_string()
+    } else {
+        return VolumeCondition {
+            abnormal: false,
+            message: "logical volume is active and healthy".into(),
+        };
+    };
+
+    VolumeCondition {
+        abnormal: true,
+        message,
     }
+}
 
-    async fn list_snapshots(
-        &self,
-        _request: Request<ListSnapshotsRequest>,
-    ) -> Result<Response<ListSnapshotsResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+/// A volume or snapshot to copy the contents of a new volume from
+struct CopySource {
+    /// The tag recording the source on the copy
+    tag: String,
+
+    name: String,
+    volume_group: String,
+    capacity_bytes: u64,
+
+    /// Whether the source is encrypted, which the copy inherits
+    encrypted: bool,
+}
+
+/// Get the volume or snapshot that a volume was copied from, if any
+fn content_source(lv: &LogicalVolume) -> Option<VolumeContentSource> {
+    lv.tags.iter().find_map(|tag| {
+        if let Some(volume_id) = tag.strip_prefix(SOURCE_VOLUME_TAG_PREFIX) {
+            Some(ContentType::Volume(VolumeSource {
+                volume_id: volume_id.into(),
+            }))
+        } else {
+            tag.strip_prefix(SOURCE_SNAPSHOT_TAG_PREFIX)
+                .map(|snapshot_id| {
+                    ContentType::Snapshot(SnapshotSource {
+                        snapshot_id: snapshot_id.into(),
+                    })
+                })
+        }
+        .map(|r#type| VolumeContentSource {
+            r#type: Some(r#type),
+        })
+    })
+}
+
+/// Get the filesystem that a volume was formatted with, or [None] for raw block volumes
+///
+/// Volumes created before filesystems were tagged were always formatted with
+/// [DEFAULT_FS_TYPE], and are recognised by not using the current naming scheme.
+fn volume_fs_type(lv: &LogicalVolume) -> Option<&str> {
+    let tagged = lv.tags.iter().find_map(|tag| {
+        tag.strip_prefix(FS_TYPE_TAG)
+            .and_then(|tag| tag.strip_prefix('='))
+    });
+
+    match tagged {
+        None if !lv.name.starts_with(VOLUME_NAME_PREFIX)
+            && !lv.name.starts_with(SNAPSHOT_NAME_PREFIX) =>
+        {
+            Some(DEFAULT_FS_TYPE)
+        }
+        tagged => tagged,
     }
+}
 
-    async fn controller_expand_volume(
-        &self,
-        _request: Request<ControllerExpandVolumeRequest>,
-    ) -> Result<Response<ControllerExpandVolumeResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+/// Whether a [LogicalVolume] has been encrypted with LUKS
+fn is_encrypted(lv: &LogicalVolume) -> bool {
+    lv.tags.iter().any(|tag| tag == ENCRYPTED_TAG)
+}
+
+/// Check whether a capability can be satisfied by a volume formatted with `fs_type`
+/// (or a raw block volume if [None]), returning the reason if not.
+fn unsupported_capability(cap: &VolumeCapability, fs_type: Option<&str>) -> Option<String> {
+    // Volumes are local to a node and the node does not advertise support for the
+    //  single writer modes, so only plain single node access is possible
+    let mode = match &cap.access_mode {
+        Some(access_mode) => access_mode.mode(),
+        None => return Some("missing access mode in volume capability".into()),
+    };
+    if !matches!(mode, Mode::SingleNodeWriter | Mode::SingleNodeReaderOnly) {
+        return Some(format!(
+            "unsupported access mode {:?}: only single node access is supported",
+            mode
+        ));
     }
 
-    async fn controller_get_volume(
-        &self,
-        _request: Request<ControllerGetVolumeRequest>,
-    ) -> Result<Response<ControllerGetVolumeResponse>, Status> {
-        Err(Status::unimplemented("not implemented"))
+    match (&cap.access_type, fs_type) {
+        (None, _) => Some("missing access type in volume capability".into()),
+        (Some(AccessType::Block(_)), None) => None,
+        (Some(AccessType::Block(_)), Some(fs_type)) => Some(format!(
+            "volume is formatted with `{}` and cannot be accessed as a raw block device",
+            fs_type
+        )),
+        (Some(AccessType::Mount(_)), None) => {
+            Some("volume is a raw block volume and cannot be mounted".into())
+        }
+        (Some(AccessType::Mount(mount)), Some(fs_type)) => {
+            if !mount.fs_type.is_empty() && mount.fs_type != fs_type {
+                Some(format!(
+                    "volume is formatted with `{}`, but `{}` was requested",
+                    fs_type, mount.fs_type
+                ))
+            } else if let Some(flag) = mount
+                .mount_flags
+                .iter()
+                .find(|flag| matches!(flag.as_str(), "dev" | "suid"))
+            {
+                Some(format!("mount flag `{}` is not allowed", flag))
+            } else if !mount.volume_mount_group.is_empty() {
+                Some("volume mount groups are not supported".into())
+            } else {
+                None
+            }
+        }
     }
 }
 
-/// Hashes a resource for safe usage
-pub(crate) fn hash_resource<T>(obj: T) -> String
-where
-    T: Hash,
-{
-    let mut hasher = DefaultHasher::new();
-    obj.hash(&mut hasher);
+/// Check that a volume context and the parameters it was provisioned with match the
+/// volume it describes, returning the mismatch if not.
+fn inconsistent_context(
+    context: &HashMap<String, String>,
+    parameters: &HashMap<String, String>,
+    lv: &LogicalVolume,
+    fs_type: Option<&str>,
+) -> Option<String> {
+    let volume_group = parameters
+        .get(VOLUME_GROUP_PARAMETER)
+        .map(String::as_str)
+        .unwrap_or_default();
+    let mismatch = parse_format_options(parameters, fs_type)
+        .ok()
+        .and_then(|options| mismatched_parameters(lv, volume_group, fs_type, &options));
+    if mismatch.is_some() {
+        return mismatch;
+    }
 
-    format!("{:X}", hasher.finish())
+    // Copies keep the encryption of their source, whatever the parameters
+    match requested_encryption(parameters) {
+        Ok(encrypted) if encrypted != is_encrypted(lv) && !(is_copy(lv) && is_encrypted(lv)) => {
+            return Some(format!(
+                "parameters request encrypted={}, but the volume is encrypted={}",
+                encrypted,
+                is_encrypted(lv)
+            ))
+        }
+        Err(status) => return Some(status.message().to_string()),
+        _ => (),
+    }
+
+    if let Some(name) = context.get("name") {
+        if *name != lv.name {
+            return Some(format!(
+                "volume context refers to logical volume `{}`, but the volume is `{}`",
+                name, lv.name
+            ));
+        }
+    }
+
+    match (context.get(FS_TYPE_CONTEXT_KEY), fs_type) {
+        (Some(context_fs), Some(fs_type)) if context_fs != fs_type => Some(format!(
+            "volume context has filesystem `{}`, but the volume is formatted with `{}`",
+            context_fs, fs_type
+        )),
+        (Some(context_fs), None) => Some(format!(
+            "volume context has filesystem `{}`, but the volume is a raw block volume",
+            context_fs
+        )),
+        _ => None,
+    }
+}
+
+/// Check that an existing volume was provisioned in `volume_group` (or any, if empty)
+/// and with the filesystem and format options requested, returning the mismatch if
+/// not.
+///
+/// Note: Copies keep the filesystem of their source, so only their volume group is
+/// checked. Volumes created by older releases were not tagged with their format
+/// options, so those are not checked either.
+fn mismatched_parameters(
+    lv: &LogicalVolume,
+    volume_group: &str,
+    fs_type: Option<&str>,
+    format_options: &FormatOptions,
+) -> Option<String> {
+    if !volume_group.is_empty() && lv.volume_group != volume_group {
+        return Some(format!(
+            "volume is in volume group `{}`, but `{}` was requested",
+            lv.volume_group, volume_group
+        ));
+    }
+
+    if is_copy(lv) {
+        return None;
+    }
+
+    match (volume_fs_type(lv), fs_type) {
+        (Some(found), Some(requested)) if found != requested => {
+            return Some(format!(
+                "volume is formatted with `{}`, but `{}` was requested",
+                found, requested
+            ))
+        }
+        (Some(found), None) => {
+            return Some(format!(
+                "volume is formatted with `{}`, but a raw block volume was requested",
+                found
+            ))
+        }
+        _ => (),
+    }
+
+    let tagged = lv
+        .tags
+        .iter()
+        .find(|tag| tag.starts_with(FORMAT_OPTIONS_TAG_PREFIX));
+    let requested = format_options_tag(format_options);
+    match tagged {
+        Some(tagged) if fs_type.is_some() && *tagged != requested => Some(format!(
+            "volume was formatted with `{}`, but `{}` was requested",
+            tagged, requested
+        )),
+        _ => None,
+    }
+}
+
+/// Check whether the signature `found` on an untagged volume is the filesystem that
+/// an earlier attempt at the same request would have formatted it with, returning the
+/// reason if not.
+///
+/// Note: The options of an existing filesystem are only known from the tag recorded
+/// when the volume was created.
+fn mismatched_filesystem(
+    lv: &LogicalVolume,
+    found: Option<&str>,
+    fs_type: &str,
+    format_options: &FormatOptions,
+) -> Option<String> {
+    let found = found.unwrap_or("unknown");
+    if found != fs_type {
+        return Some(format!(
+            "volume already contains a `{}` signature, but `{}` was requested",
+            found, fs_type
+        ));
+    }
+
+    let tagged = lv
+        .tags
+        .iter()
+        .find(|tag| tag.starts_with(FORMAT_OPTIONS_TAG_PREFIX));
+    let requested = format_options_tag(format_options);
+    match tagged {
+        Some(tagged) if *tagged == requested => None,
+        Some(tagged) => Some(format!(
+            "volume was formatted with `{}`, but `{}` was requested",
+            tagged, requested
+        )),
+        None => Some(format!(
+            "volume was formatted with unknown options, but `{}` was requested",
+            requested
+        )),
+    }
+}
+
+/// Whether a [LogicalVolume] was copied from another volume or snapshot
+fn is_copy(lv: &LogicalVolume) -> bool {
+    lv.tags.iter().any(|tag| {
+        tag.starts_with(SOURCE_VOLUME_TAG_PREFIX) || tag.starts_with(SOURCE_SNAPSHOT_TAG_PREFIX)
+    })
+}
+
+/// Construct the tag recording the options a volume was formatted with
+fn format_options_tag(options: &FormatOptions) -> String {
+    format!(
+        "{}{}:{}:{}",
+        FORMAT_OPTIONS_TAG_PREFIX, options.block_size, options.inode_size, options.reflink
+    )
+}
+
+/// Get the filesystem requested by the capabilities of a request, or [None] if
+/// raw block access was requested.
+///
+/// Note: All mount capabilities must agree on the filesystem, and unspecified
+/// filesystems default to [DEFAULT_FS_TYPE]. A volume cannot be requested as
+/// both a block and a mount volume.
+fn requested_fs_type(capabilities: &[VolumeCapability]) -> Result<Option<String>, Status> {
+    let is_block = capabilities
+        .iter()
+        .any(|cap| matches!(cap.access_type, Some(AccessType::Block(_))));
+    let is_mount = capabilities
+        .iter()
+        .any(|cap| matches!(cap.access_type, Some(AccessType::Mount(_))));
+
+    if is_block && is_mount {
+        return Err(Status::invalid_argument(
+            "volume capabilities cannot request both block and mount access",
+        ));
+    }
+    if is_block {
+        return Ok(None);
+    }
+
+    let mut fs_types: Vec<&str> = capabilities
+        .iter()
+        .filter_map(|cap| match &cap.access_type {
+            Some(AccessType::Mount(mount)) if !mount.fs_type.is_empty() => {
+                Some(mount.fs_type.as_str())
+            }
+            _ => None,
+        })
+        .collect();
+    fs_types.sort_unstable();
+    fs_types.dedup();
+
+    let fs_type = match fs_types[..] {
+        [] => DEFAULT_FS_TYPE,
+        [fs_type] => fs_type,
+        _ => {
+            return Err(Status::invalid_argument(format!(
+                "volume capabilities request conflicting filesystems: {:?}",
+                fs_types
+            )))
+        }
+    };
+
+    if !SUPPORTED_FS_TYPES.contains(&fs_type) {
+        return Err(Status::invalid_argument(format!(
+            "unsupported filesystem `{}`: must be one of {:?}",
+            fs_type, SUPPORTED_FS_TYPES
+        )));
+    }
+
+    Ok(Some(fs_type.to_string()))
+}
+
+/// Parse the (optional) mkfs options from the parameters of a request, checking that
+/// they can be honored by `fs_type` (if any) before anything gets allocated.
+fn parse_format_options(
+    parameters: &HashMap<String, String>,
+    fs_type: Option<&str>,
+) -> Result<FormatOptions, Status> {
+    let parse_size = |key: &str| -> Result<u32, Status> {
+        parameters
+            .get(key)
+            .map(|value| {
+                value.parse().map_err(|err: std::num::ParseIntError| {
+                    Status::invalid_argument(format!(
+                        "parameter `{}` must be a valid size in bytes: {}",
+                        key, err
+                    ))
+                })
+            })
+            .unwrap_or(Ok(0))
+    };
+
+    let reflink = match parameters.get(REFLINK_PARAMETER).map(String::as_str) {
+        None => Reflink::Default,
+        Some("true") => Reflink::Enabled,
+        Some("false") => Reflink::Disabled,
+        Some(other) => {
+            return Err(Status::invalid_argument(format!(
+                "parameter `{}` must be either `true` or `false`: got `{}`",
+                REFLINK_PARAMETER, other
+            )))
+        }
+    };
+
+    let options = FormatOptions {
+        block_size: parse_size(BLOCK_SIZE_PARAMETER)?,
+        inode_size: parse_size(INODE_SIZE_PARAMETER)?,
+        reflink: reflink.into(),
+    };
+
+    if let Some(fs_type) = fs_type {
+        fs_type.parse::<Filesystem>()?.validate_options(&options)?;
+    }
+
+    Ok(options)
+}
+
+/// Parse whether the StorageClass parameters request an encrypted volume
+fn requested_encryption(parameters: &HashMap<String, String>) -> Result<bool, Status> {
+    match parameters.get(ENCRYPTED_PARAMETER).map(String::as_str) {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(other) => Err(Status::invalid_argument(format!(
+            "parameter `{}` must be either `true` or `false`: got `{}`",
+            ENCRYPTED_PARAMETER, other
+        ))),
+    }
+}
+
+/// Get the extent size of a volume group (or the default one if empty), which
+/// volumed rounds the sizes of all volumes up to.
+async fn extent_size(client: &mut Client, volume_group: &str) -> Result<u64, Status> {
+    let free = client
+        .get_free_bytes(GetFreeBytesRequest {
+            volume_group: volume_group.to_string(),
+        })
+        .await?
+        .into_inner();
+
+    Ok(free.extent_size)
+}
+
+/// Derive the name of the LV backing a CSI resource.
+///
+/// CSI names may be arbitrarily long and contain characters not allowed by LVM,
+/// so the LV is instead named after the hex-encoded SHA-256 digest of the CSI name,
+/// prefixed by the kind of resource (e.g. `csi-vol-<digest>`). This is stable across
+/// releases and toolchains. The original CSI name is stored in a `csi-name=` tag on
+/// the LV (see [name_tag]) so that collisions can be detected.
+pub(crate) fn hash_resource(prefix: &str, name: &str) -> String {
+    format!("{}{:x}", prefix, Sha256::digest(name.as_bytes()))
+}
+
+/// Construct the tag recording the CSI name of a resource.
+///
+/// Note: LVM only allows a few characters in tags, so the name is hex-encoded.
+fn name_tag(name: &str) -> String {
+    let encoded: String = name.bytes().map(|byte| format!("{:02x}", byte)).collect();
+
+    format!("{}{}", NAME_TAG_PREFIX, encoded)
+}
+
+/// Construct the tag recording the CSI name of a resource created by an older release
+fn legacy_name_tag(name: &str) -> String {
+    format!("{}{}", LEGACY_NAME_TAG_PREFIX, name)
+}
+
+/// Find the LV backing the CSI volume with the specified name, if it exists.
+///
+/// Volumes created by older releases were named with a hash that is not stable
+/// across toolchains, so those are looked up by their legacy `name=` tag instead.
+pub(crate) async fn find_volume(
+    client: &mut Client,
+    name: &str,
+) -> Result<Option<LogicalVolume>, Status> {
+    let tag = name_tag(name);
+    let volume = client
+        .get_logical_volume(Request::new(GetLvRequest {
+            identifier: Some(Identifier::Name(hash_resource(VOLUME_NAME_PREFIX, name))),
+            volume_group: String::new(),
+        }))
+        .await;
+
+    match volume {
+        Ok(volume) => {
+            let volume = volume.into_inner();
+
+            // Make sure that the LV actually belongs to this volume
+            if !volume.tags.contains(&tag) {
+                return Err(Status::already_exists(format!(
+                    "logical volume `{}` exists, but does not belong to volume `{}`: found tags {:?}",
+                    volume.name, name, volume.tags,
+                )));
+            }
+
+            Ok(Some(volume))
+        }
+        Err(status) if status.code() == Code::NotFound => Ok(client
+            .get_lv_list(Request::new(GetLvListRequest::default()))
+            .await?
+            .into_inner()
+            .volumes
+            .into_iter()
+            .find(|volume| volume.tags.contains(&legacy_name_tag(name)))),
+        Err(status) => Err(status),
+    }
+}
+
+/// Find the LV backing the CSI snapshot with the specified name, if it exists.
+///
+/// Note: Unlike volumes, older releases never created snapshots, so there are no
+/// legacy names to fall back to.
+async fn find_snapshot(client: &mut Client, name: &str) -> Result<Option<LvSnapshot>, Status> {
+    let tag = name_tag(name);
+    let snapshot = client
+        .get_snapshot(Request::new(GetSnapshotRequest {
+            identifier: Some(SnapshotIdentifier::Name(hash_resource(
+                SNAPSHOT_NAME_PREFIX,
+                name,
+            ))),
+            volume_group: String::new(),
+        }))
+        .await;
+
+    match snapshot {
+        Ok(snapshot) => {
+            let snapshot = snapshot.into_inner();
+
+            // Make sure that the LV actually belongs to this snapshot
+            if !snapshot.tags.contains(&tag) {
+                return Err(Status::already_exists(format!(
+                    "snapshot `{}` exists, but does not belong to snapshot `{}`: found tags {:?}",
+                    snapshot.name, name, snapshot.tags,
+                )));
+            }
+
+            Ok(Some(snapshot))
+        }
+        Err(status) if status.code() == Code::NotFound => Ok(None),
+        Err(status) => Err(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LV names identify existing volumes on disk, so they must never change
+    #[test]
+    fn hash_volume_name() {
+        assert_eq!(
+            hash_resource(
+                VOLUME_NAME_PREFIX,
+                "pvc-0b6e1c2a-4f5d-4c3b-9a8e-7d6f5e4c3b2a"
+            ),
+            "csi-vol-29069c67923d5e286440c73a94558b6d493de612da0003c4f047928ce7a367c3"
+        );
+    }
+
+    #[test]
+    fn hash_snapshot_name() {
+        assert_eq!(
+            hash_resource(
+                SNAPSHOT_NAME_PREFIX,
+                "snapshot-5c4b3a29-1807-4f6e-8d5c-4b3a29180716"
+            ),
+            "csi-snap-fbfb31da3446251785d1861ac5851f57a4d7d66779e321c4436301efad0b245a"
+        );
+    }
+
+    #[test]
+    fn encode_name_tag() {
+        assert_eq!(name_tag("pvc a/b"), "csi-name=70766320612f62");
+    }
+
+    #[test]
+    fn takes_over_only_requested_filesystems() {
+        let options = FormatOptions {
+            block_size: 4096,
+            inode_size: 0,
+            reflink: Reflink::Default.into(),
+        };
+        let volume = |tags: &[&str]| LogicalVolume {
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            ..Default::default()
+        };
+        let tagged = volume(&[&format_options_tag(&options)]);
+
+        assert_eq!(
+            mismatched_filesystem(&tagged, Some("xfs"), "xfs", &options),
+            None
+        );
+
+        for (lv, found) in [
+            // Formatted with something else
+            (&tagged, Some("ext4")),
+            (&tagged, None),
+            // Formatted with different or unknown options
+            (&volume(&["format-options=1024:0:0"]), Some("xfs")),
+            (&volume(&[]), Some("xfs")),
+        ] {
+            assert!(
+                mismatched_filesystem(lv, found, "xfs", &options).is_some(),
+                "{:?} on {:?}",
+                found,
+                lv.tags
+            );
+        }
+    }
 }
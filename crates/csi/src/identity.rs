@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use mountd::spec::mount_service_client::MountServiceClient;
 use tonic::{transport::Channel, Request, Response, Status};
-use volumed::spec::{volume_service_client::VolumeServiceClient, Empty};
+use volumed::{auth, spec::GetFreeBytesRequest};
 
 use crate::csi::v1_7_0::{
     identity_server::{Identity, IdentityServer},
@@ -14,6 +14,9 @@ use crate::csi::v1_7_0::{
 pub enum Verifier {
     Controller,
     Node,
+
+    /// A cluster controller, which is ready as long as any of its nodes is
+    Cluster(Vec<auth::Client>),
 }
 
 impl Verifier {
@@ -22,12 +25,12 @@ impl Verifier {
             Self::Controller => {
                 let mut client = request
                     .extensions()
-                    .get::<VolumeServiceClient<Channel>>()
+                    .get::<auth::Client>()
                     .expect("could not get volumed client")
                     .clone();
 
                 client
-                    .get_free_bytes(Request::new(Empty::default()))
+                    .get_free_bytes(Request::new(GetFreeBytesRequest::default()))
                     .await
                     .map(|_| true)
                     .ok()
@@ -42,6 +45,21 @@ impl Verifier {
                 // TODO
                 Some(true)
             }
+            Self::Cluster(clients) => {
+                for client in clients {
+                    let reachable = client
+                        .clone()
+                        .get_free_bytes(Request::new(GetFreeBytesRequest::default()))
+                        .await
+                        .is_ok();
+
+                    if reachable {
+                        return Some(true);
+                    }
+                }
+
+                Some(false)
+            }
         }
     }
 }
@@ -113,8 +131,7 @@ impl Identity for RLVMIdentity {
             capabilities: vec![
                 plugin_capability!(Service, ControllerService),
                 plugin_capability!(Service, VolumeAccessibilityConstraints),
-                // plugin_capability!(VolumeExpansion, Online),
-                // plugin_capability!(VolumeExpansion, Offline),
+                plugin_capability!(VolumeExpansion, Online),
             ],
         };
 
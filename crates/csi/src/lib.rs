@@ -1,6 +1,17 @@
+use std::collections::HashMap;
+
+use csi::v1_7_0::{
+    ControllerExpandVolumeRequest, CreateSnapshotRequest, CreateVolumeRequest,
+    ListSnapshotsRequest, NodeExpandVolumeRequest, NodePublishVolumeRequest,
+    NodeStageVolumeRequest,
+};
+
+pub mod cluster;
 pub mod controller;
 pub mod identity;
 pub mod node;
+pub mod node_id;
+pub mod topology;
 
 pub mod csi {
     pub mod v1_7_0 {
@@ -10,3 +21,60 @@ pub mod csi {
 
 /// Allow for a minimum volume size of 512M (must be multiple of 512)
 pub const MIN_VOLUME_SIZE_BYTES: usize = 512 * 1024 * 1024;
+
+/// Filesystems supported by volumed, along with the one used when the CO does not
+/// request a specific one
+pub use volumed::filesystem::{DEFAULT_FS_TYPE, SUPPORTED_FS_TYPES};
+
+/// Key of the volume context holding the filesystem of a volume
+pub const FS_TYPE_CONTEXT_KEY: &str = "fsType";
+
+/// Key of the volume context marking a volume as encrypted
+pub const ENCRYPTED_CONTEXT_KEY: &str = "encrypted";
+
+/// Key of the volume context marking a volume whose filesystem is smaller than the
+/// volume (e.g. a copy of a smaller source), which gets grown once it is staged
+pub const GROW_FS_CONTEXT_KEY: &str = "growFilesystem";
+
+/// Key of the CSI secret holding the passphrase of encrypted volumes
+pub const PASSPHRASE_SECRET_KEY: &str = "encryptionPassphrase";
+
+/// Placeholder logged in place of the values of secrets
+const REDACTED: &str = "<redacted>";
+
+/// A request carrying CSI secrets, which must never end up in the logs
+pub(crate) trait Redact: Clone {
+    fn secrets_mut(&mut self) -> &mut HashMap<String, String>;
+
+    /// Get a copy of the request with the values of its secrets replaced, for logging
+    fn redacted(&self) -> Self {
+        let mut redacted = self.clone();
+        for value in redacted.secrets_mut().values_mut() {
+            *value = REDACTED.into();
+        }
+
+        redacted
+    }
+}
+
+macro_rules! impl_redact {
+    ($($request:ty),*) => {
+        $(
+            impl Redact for $request {
+                fn secrets_mut(&mut self) -> &mut HashMap<String, String> {
+                    &mut self.secrets
+                }
+            }
+        )*
+    };
+}
+
+impl_redact!(
+    ControllerExpandVolumeRequest,
+    CreateSnapshotRequest,
+    CreateVolumeRequest,
+    ListSnapshotsRequest,
+    NodeExpandVolumeRequest,
+    NodePublishVolumeRequest,
+    NodeStageVolumeRequest
+);
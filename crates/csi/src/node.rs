@@ -1,19 +1,28 @@
 use std::{collections::HashMap, path::Path};
 
 use mountd::spec::{
-    mount_service_client::MountServiceClient, GetLvmBlockPathRequest, Mount, MountFlag,
-    MountRequest, UnmountRequest,
+    mount_service_client::MountServiceClient, BlockDevice, CloseEncryptedDeviceRequest,
+    GetFilesystemStatsRequest, GetLvmBlockPathRequest, GrowFilesystemRequest, Mount, MountFlag,
+    MountRequest, OpenEncryptedDeviceRequest, UnmountRequest,
 };
 use tonic::{transport::Channel, Request, Response, Status};
 use uuid::Uuid;
 
 use crate::csi::v1_7_0::{
     node_server::{Node, NodeServer},
-    volume_capability::access_mode::Mode,
-    NodeGetCapabilitiesRequest, NodeGetCapabilitiesResponse, NodeGetInfoRequest,
-    NodeGetInfoResponse, NodePublishVolumeRequest, NodePublishVolumeResponse,
-    NodeStageVolumeRequest, NodeStageVolumeResponse, NodeUnpublishVolumeRequest,
-    NodeUnpublishVolumeResponse, NodeUnstageVolumeRequest, NodeUnstageVolumeResponse, Topology,
+    volume_capability::{access_mode::Mode, AccessType},
+    volume_usage::Unit,
+    NodeExpandVolumeRequest, NodeExpandVolumeResponse, NodeGetCapabilitiesRequest,
+    NodeGetCapabilitiesResponse, NodeGetInfoRequest, NodeGetInfoResponse,
+    NodeGetVolumeStatsRequest, NodeGetVolumeStatsResponse, NodePublishVolumeRequest,
+    NodePublishVolumeResponse, NodeStageVolumeRequest, NodeStageVolumeResponse,
+    NodeUnpublishVolumeRequest, NodeUnpublishVolumeResponse, NodeUnstageVolumeRequest,
+    NodeUnstageVolumeResponse, Topology, VolumeCapability, VolumeCondition, VolumeUsage,
+};
+
+use crate::{
+    cluster, Redact, DEFAULT_FS_TYPE, ENCRYPTED_CONTEXT_KEY, FS_TYPE_CONTEXT_KEY,
+    GROW_FS_CONTEXT_KEY, PASSPHRASE_SECRET_KEY,
 };
 
 type Client = MountServiceClient<Channel>;
@@ -21,12 +30,27 @@ type Client = MountServiceClient<Channel>;
 #[derive(Debug)]
 pub struct RLVMNode {
     node_id: Uuid,
+
+    /// Topology of the node, as advertised to the CO
+    topology: Topology,
 }
 
 impl RLVMNode {
     /// Create a node which tracks the specified volume groups
-    pub fn new(node_id: Uuid) -> Self {
-        RLVMNode { node_id }
+    pub fn new(node_id: Uuid, topology: Topology) -> Self {
+        RLVMNode { node_id, topology }
+    }
+
+    /// Get the ID of a volume as known to the local volumed, verifying and stripping the
+    /// node that the cluster controller qualifies IDs with (`<node>:<id>`)
+    fn local_volume_id(&self, volume_id: &str) -> Result<String, Status> {
+        match cluster::decode_id(volume_id)? {
+            (Some(node), _) if node != self.node_id => Err(Status::not_found(format!(
+                "volume `{}` belongs to node `{}`, not to this one (`{}`)",
+                volume_id, node, self.node_id
+            ))),
+            (_, id) => Ok(id.to_string()),
+        }
     }
 
     /// Convert the controller into an intercepted service
@@ -36,6 +60,63 @@ impl RLVMNode {
     }
 }
 
+/// Whether the volume context marks a volume as encrypted
+fn is_encrypted(volume_context: &HashMap<String, String>) -> bool {
+    volume_context
+        .get(ENCRYPTED_CONTEXT_KEY)
+        .map(|value| value == "true")
+        .unwrap_or_default()
+}
+
+/// Get the device to use for a volume, opening its dm-crypt mapping first if it
+/// is encrypted.
+///
+/// Note: The passphrase may be empty if the mapping has already been opened while
+/// staging the volume.
+async fn volume_device(
+    client: &mut Client,
+    volume_id: &str,
+    encrypted: bool,
+    passphrase: &str,
+) -> Result<BlockDevice, Status> {
+    if encrypted {
+        client
+            .open_encrypted_device(Request::new(OpenEncryptedDeviceRequest {
+                uuid: volume_id.to_string(),
+                passphrase: passphrase.to_string(),
+            }))
+            .await
+            .map(Response::into_inner)
+    } else {
+        client
+            .get_lvm_block_path(Request::new(GetLvmBlockPathRequest {
+                uuid: volume_id.to_string(),
+            }))
+            .await
+            .map(Response::into_inner)
+    }
+}
+
+/// Whether a capability requests raw block access to a volume
+fn is_block_access(capability: Option<&VolumeCapability>) -> bool {
+    matches!(
+        capability.and_then(|cap| cap.access_type.as_ref()),
+        Some(AccessType::Block(_))
+    )
+}
+
+/// Whether a volume has a filesystem that can be grown.
+///
+/// Note: The capability is optional when expanding a volume, in which case we go by
+/// the volume path instead: raw block volumes are bind mounted onto a file, while
+/// filesystems are mounted onto a directory.
+fn has_filesystem(capability: Option<&VolumeCapability>, volume_path: &Path) -> bool {
+    match capability {
+        Some(_) => !is_block_access(capability),
+        None => volume_path.is_dir(),
+    }
+}
+
 /// Construct the needed structure for a controller capability.
 ///
 /// Takes the capability type ([crate::csi::v1_7_0::controller_capability::Type]) and
@@ -69,14 +150,15 @@ impl Node for RLVMNode {
         request: Request<NodeStageVolumeRequest>,
     ) -> Result<Response<NodeStageVolumeResponse>, Status> {
         let mut client = request.extensions().get::<Client>().unwrap().clone();
-        let req = request.into_inner();
+        let mut req = request.into_inner();
 
-        log::info!("got NodeStageVolume request: {:?}", req);
+        log::info!("got NodeStageVolume request: {:?}", req.redacted());
 
         // Validate args
         if req.volume_id.is_empty() {
             return Err(Status::invalid_argument("`volume_id` cannot be empty"));
         }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
         if req.staging_target_path.is_empty() {
             return Err(Status::invalid_argument(
                 "`staging_target_path` cannot be empty",
@@ -88,13 +170,22 @@ impl Node for RLVMNode {
             ));
         }
 
+        // Encrypted volumes need their passphrase to be opened
+        let encrypted = is_encrypted(&req.volume_context);
+        let passphrase = match req.secrets.get(PASSPHRASE_SECRET_KEY) {
+            Some(passphrase) => passphrase.as_str(),
+            None if encrypted => {
+                return Err(Status::invalid_argument(format!(
+                    "encrypted volumes require the `{}` secret",
+                    PASSPHRASE_SECRET_KEY
+                )))
+            }
+            None => "",
+        };
+
         // Attempt to get the device matching the volume ID from the mountd service
-        let block_device = client
-            .get_lvm_block_path(Request::new(GetLvmBlockPathRequest {
-                uuid: req.volume_id.clone(),
-            }))
-            .await?
-            .into_inner();
+        let block_device =
+            volume_device(&mut client, &req.volume_id, encrypted, passphrase).await?;
         let mount_src = Path::new(&block_device.path);
         let mount_dst = Path::new(&req.staging_target_path);
 
@@ -106,6 +197,11 @@ impl Node for RLVMNode {
             )));
         }
 
+        // Raw block volumes are bound directly to the target path when published
+        if is_block_access(req.volume_capability.as_ref()) {
+            return Ok(Response::new(NodeStageVolumeResponse {}));
+        }
+
         if !mount_dst.exists() {
             return Err(Status::failed_precondition(format!(
                 "volume with id `{}` does not have a valid mount destination: {}",
@@ -113,6 +209,31 @@ impl Node for RLVMNode {
             )));
         }
 
+        // Prefer the filesystem requested by the CO, falling back to what the
+        //  volume was created with
+        let fs_type = match req
+            .volume_capability
+            .as_ref()
+            .and_then(|cap| cap.access_type.as_ref())
+        {
+            Some(AccessType::Mount(mount)) if !mount.fs_type.is_empty() => mount.fs_type.clone(),
+            _ => req
+                .volume_context
+                .get(FS_TYPE_CONTEXT_KEY)
+                .cloned()
+                .unwrap_or_else(|| DEFAULT_FS_TYPE.into()),
+        };
+
+        // Pass along any extra mount flags requested by the CO
+        let options = match req
+            .volume_capability
+            .as_ref()
+            .and_then(|cap| cap.access_type.as_ref())
+        {
+            Some(AccessType::Mount(mount)) => mount.mount_flags.clone(),
+            _ => vec![],
+        };
+
         // Generate flags as needed
         let readonly = req
             .volume_capability
@@ -132,9 +253,28 @@ impl Node for RLVMNode {
                 } else {
                     vec![]
                 },
+                fs_type,
+                options,
             }))
-            .await
-            .map(|_| Response::new(NodeStageVolumeResponse {}))
+            .await?;
+
+        // Copies of smaller sources only have a filesystem as large as their source.
+        //  Growing it is a no-op once it fills the volume, so this is safe to repeat.
+        let grow_fs = req
+            .volume_context
+            .get(GROW_FS_CONTEXT_KEY)
+            .map(|value| value == "true")
+            .unwrap_or_default();
+        if grow_fs && !readonly {
+            client
+                .grow_filesystem(Request::new(GrowFilesystemRequest {
+                    path: mount_dst.to_string_lossy().to_string(),
+                    passphrase: passphrase.to_string(),
+                }))
+                .await?;
+        }
+
+        Ok(Response::new(NodeStageVolumeResponse {}))
     }
 
     async fn node_unstage_volume(
@@ -142,7 +282,7 @@ impl Node for RLVMNode {
         request: Request<NodeUnstageVolumeRequest>,
     ) -> Result<Response<NodeUnstageVolumeResponse>, Status> {
         let mut client = request.extensions().get::<Client>().unwrap().clone();
-        let req = request.into_inner();
+        let mut req = request.into_inner();
 
         log::info!("got NodeUnstageVolume request: {:?}", req);
 
@@ -150,6 +290,7 @@ impl Node for RLVMNode {
         if req.volume_id.is_empty() {
             return Err(Status::invalid_argument("`volume_id` cannot be empty"));
         }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
         if req.staging_target_path.is_empty() {
             return Err(Status::invalid_argument(
                 "`staging_target_path` cannot be empty",
@@ -178,6 +319,13 @@ impl Node for RLVMNode {
             .unmount(Request::new(UnmountRequest {
                 path: req.staging_target_path,
             }))
+            .await?;
+
+        // Close the dm-crypt mapping of encrypted volumes (a no-op otherwise)
+        client
+            .close_encrypted_device(Request::new(CloseEncryptedDeviceRequest {
+                uuid: req.volume_id,
+            }))
             .await
             .map(|_| Response::new(NodeUnstageVolumeResponse {}))
     }
@@ -187,14 +335,15 @@ impl Node for RLVMNode {
         request: Request<NodePublishVolumeRequest>,
     ) -> Result<Response<NodePublishVolumeResponse>, Status> {
         let mut client = request.extensions().get::<Client>().unwrap().clone();
-        let req = request.into_inner();
+        let mut req = request.into_inner();
 
-        log::info!("got NodePublish request: {:?}", req);
+        log::info!("got NodePublish request: {:?}", req.redacted());
 
         // Validate args
         if req.volume_id.is_empty() {
             return Err(Status::invalid_argument("`volume_id` cannot be empty"));
         }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
         if req.staging_target_path.is_empty() {
             return Err(Status::invalid_argument(
                 "`staging_target_path` cannot be empty",
@@ -206,13 +355,61 @@ impl Node for RLVMNode {
             ));
         }
 
-        // Verify that the volume ID is valid
-        client
-            .get_lvm_block_path(Request::new(GetLvmBlockPathRequest {
-                uuid: req.volume_id.clone(),
-            }))
-            .await?
-            .into_inner();
+        // Verify that the volume ID is valid. Encrypted volumes have already been
+        //  opened while staging, so no passphrase is needed
+        let block_device = volume_device(
+            &mut client,
+            &req.volume_id,
+            is_encrypted(&req.volume_context),
+            "",
+        )
+        .await?;
+
+        // Generate flags as needed
+        let readonly = req
+            .volume_capability
+            .as_ref()
+            .and_then(|cap| cap.access_mode.as_ref())
+            .map(|access_mode| access_mode.mode == Mode::SingleNodeReaderOnly as i32)
+            .unwrap_or_default();
+
+        // Raw block volumes are bound straight from the device onto a file
+        if is_block_access(req.volume_capability.as_ref()) {
+            let mount_dst = Path::new(&req.target_path);
+
+            // It is our responsibility to create this path...
+            if let Some(parent) = mount_dst.parent() {
+                tokio::fs::create_dir_all(parent).await.map_err(|err| {
+                    Status::internal(format!("could not create parent of target path: {}", err))
+                })?;
+            }
+            tokio::fs::OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(mount_dst)
+                .await
+                .map_err(|err| {
+                    Status::internal(format!("could not create target file: {}", err))
+                })?;
+
+            return client
+                .mount_block_device(Request::new(MountRequest {
+                    mount: Some(Mount {
+                        src: block_device.path,
+                        dst: mount_dst.to_string_lossy().to_string(),
+                    }),
+                    flags: if readonly {
+                        vec![MountFlag::ReadOnly.into()]
+                    } else {
+                        vec![]
+                    },
+                    fs_type: String::new(),
+                    options: vec![],
+                }))
+                .await
+                .map(|_| Response::new(NodePublishVolumeResponse {}));
+        }
 
         let mount_src = Path::new(&req.staging_target_path);
         let mount_dst = Path::new(&req.target_path);
@@ -230,13 +427,6 @@ impl Node for RLVMNode {
             )));
         }
 
-        // Generate flags as needed
-        let readonly = req
-            .volume_capability
-            .and_then(|cap| cap.access_mode)
-            .map(|access_mode| access_mode.mode == Mode::SingleNodeReaderOnly as i32)
-            .unwrap_or_default();
-
         // Mount to the staging path
         client
             .mount(Request::new(MountRequest {
@@ -255,6 +445,10 @@ impl Node for RLVMNode {
                     },
                 ]
                 .concat(),
+
+                // Bind mounts reuse the filesystem of the staged volume
+                fs_type: String::new(),
+                options: vec![],
             }))
             .await
             .map(|_| Response::new(NodePublishVolumeResponse {}))
@@ -265,7 +459,7 @@ impl Node for RLVMNode {
         request: Request<NodeUnpublishVolumeRequest>,
     ) -> Result<Response<NodeUnpublishVolumeResponse>, Status> {
         let mut client = request.extensions().get::<Client>().unwrap().clone();
-        let req = request.into_inner();
+        let mut req = request.into_inner();
 
         log::info!("got NodeUnpublish request: {:?}", req);
 
@@ -273,6 +467,7 @@ impl Node for RLVMNode {
         if req.volume_id.is_empty() {
             return Err(Status::invalid_argument("`volume_id` cannot be empty"));
         }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
         if req.target_path.is_empty() {
             return Err(Status::invalid_argument("`target_path` cannot be empty"));
         }
@@ -294,52 +489,174 @@ impl Node for RLVMNode {
             }))
             .await?;
 
-        // It is our responsibility to delete this path...
-        tokio::fs::remove_dir(&unmount_src).await.ok();
+        // It is our responsibility to delete this path, which is a plain file for raw
+        //  block volumes...
+        let is_file = tokio::fs::metadata(&unmount_src)
+            .await
+            .map(|meta| meta.is_file())
+            .unwrap_or_default();
+        if is_file {
+            tokio::fs::remove_file(&unmount_src).await.ok();
+        } else {
+            tokio::fs::remove_dir(&unmount_src).await.ok();
+        }
 
         Ok(Response::new(NodeUnpublishVolumeResponse {}))
     }
 
-    fn node_get_volume_stats<'life0, 'async_trait>(
-        &'life0 self,
-        _request: tonic::Request<crate::csi::v1_7_0::NodeGetVolumeStatsRequest>,
-    ) -> core::pin::Pin<
-        Box<
-            dyn core::future::Future<
-                    Output = Result<
-                        tonic::Response<crate::csi::v1_7_0::NodeGetVolumeStatsResponse>,
-                        tonic::Status,
-                    >,
-                > + core::marker::Send
-                + 'async_trait,
-        >,
-    >
-    where
-        'life0: 'async_trait,
-        Self: 'async_trait,
-    {
-        todo!()
+    async fn node_get_volume_stats(
+        &self,
+        request: Request<NodeGetVolumeStatsRequest>,
+    ) -> Result<Response<NodeGetVolumeStatsResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let mut req = request.into_inner();
+
+        // Validate args
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument("`volume_id` cannot be empty"));
+        }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
+        if req.volume_path.is_empty() {
+            return Err(Status::invalid_argument("`volume_path` cannot be empty"));
+        }
+
+        // Attempt to get the device matching the volume ID from the mountd service
+        let block_device = client
+            .get_lvm_block_path(Request::new(GetLvmBlockPathRequest {
+                uuid: req.volume_id.clone(),
+            }))
+            .await?
+            .into_inner();
+
+        let volume_path = Path::new(&req.volume_path);
+        if !volume_path.exists() {
+            return Err(Status::not_found(format!(
+                "volume with id `{}` does not have a valid volume path: {}",
+                req.volume_id, req.volume_path,
+            )));
+        }
+
+        // A missing device node trumps whatever the filesystem thinks of itself
+        let device_condition = if !Path::new(&block_device.path).exists() {
+            Some(VolumeCondition {
+                abnormal: true,
+                message: format!(
+                    "device `{}` for volume with id `{}` is missing: is it active?",
+                    block_device.path, req.volume_id
+                ),
+            })
+        } else {
+            None
+        };
+
+        // Raw block volumes only have a total size to report
+        if !volume_path.is_dir() {
+            return Ok(Response::new(NodeGetVolumeStatsResponse {
+                usage: vec![VolumeUsage {
+                    total: block_device.capacity_bytes as i64,
+                    unit: Unit::Bytes.into(),
+                    ..Default::default()
+                }],
+                volume_condition: Some(device_condition.unwrap_or(VolumeCondition {
+                    abnormal: false,
+                    message: "device is present".into(),
+                })),
+            }));
+        }
+
+        let stats = client
+            .get_filesystem_stats(Request::new(GetFilesystemStatsRequest {
+                path: req.volume_path,
+            }))
+            .await?
+            .into_inner();
+
+        let volume_condition = device_condition.unwrap_or_else(|| {
+            stats
+                .condition
+                .map(|condition| VolumeCondition {
+                    abnormal: condition.abnormal,
+                    message: condition.message,
+                })
+                .unwrap_or_default()
+        });
+
+        Ok(Response::new(NodeGetVolumeStatsResponse {
+            usage: vec![
+                VolumeUsage {
+                    available: stats.available_bytes as i64,
+                    total: stats.total_bytes as i64,
+                    used: stats.used_bytes as i64,
+                    unit: Unit::Bytes.into(),
+                },
+                VolumeUsage {
+                    available: stats.available_inodes as i64,
+                    total: stats.total_inodes as i64,
+                    used: stats.used_inodes as i64,
+                    unit: Unit::Inodes.into(),
+                },
+            ],
+            volume_condition: Some(volume_condition),
+        }))
     }
 
-    fn node_expand_volume<'life0, 'async_trait>(
-        &'life0 self,
-        _request: tonic::Request<crate::csi::v1_7_0::NodeExpandVolumeRequest>,
-    ) -> core::pin::Pin<
-        Box<
-            dyn core::future::Future<
-                    Output = Result<
-                        tonic::Response<crate::csi::v1_7_0::NodeExpandVolumeResponse>,
-                        tonic::Status,
-                    >,
-                > + core::marker::Send
-                + 'async_trait,
-        >,
-    >
-    where
-        'life0: 'async_trait,
-        Self: 'async_trait,
-    {
-        todo!()
+    async fn node_expand_volume(
+        &self,
+        request: Request<NodeExpandVolumeRequest>,
+    ) -> Result<Response<NodeExpandVolumeResponse>, Status> {
+        let mut client = request.extensions().get::<Client>().unwrap().clone();
+        let mut req = request.into_inner();
+
+        log::info!("got NodeExpandVolume request: {:?}", req.redacted());
+
+        // Validate args
+        if req.volume_id.is_empty() {
+            return Err(Status::invalid_argument("`volume_id` cannot be empty"));
+        }
+        req.volume_id = self.local_volume_id(&req.volume_id)?;
+        if req.volume_path.is_empty() {
+            return Err(Status::invalid_argument("`volume_path` cannot be empty"));
+        }
+
+        // Verify that the volume ID is valid. The controller already expanded the LV,
+        //  so its size is the capacity that the volume ends up with.
+        let block_device = client
+            .get_lvm_block_path(Request::new(GetLvmBlockPathRequest {
+                uuid: req.volume_id.clone(),
+            }))
+            .await?
+            .into_inner();
+
+        let capacity_bytes = block_device.capacity_bytes as i64;
+
+        // Make sure that the volume path exists
+        let volume_path = Path::new(&req.volume_path);
+        if !volume_path.exists() {
+            return Err(Status::not_found(format!(
+                "volume with id `{}` does not have a valid volume path: {}",
+                req.volume_id, req.volume_path,
+            )));
+        }
+
+        // Raw block volumes have no filesystem to grow
+        if !has_filesystem(req.volume_capability.as_ref(), volume_path) {
+            return Ok(Response::new(NodeExpandVolumeResponse { capacity_bytes }));
+        }
+
+        // Grow the filesystem to fill the (already expanded) volume. Encrypted volumes
+        //  need their passphrase for growing their mapping first.
+        client
+            .grow_filesystem(Request::new(GrowFilesystemRequest {
+                path: volume_path.to_string_lossy().to_string(),
+                passphrase: req
+                    .secrets
+                    .get(PASSPHRASE_SECRET_KEY)
+                    .cloned()
+                    .unwrap_or_default(),
+            }))
+            .await?;
+
+        Ok(Response::new(NodeExpandVolumeResponse { capacity_bytes }))
     }
 
     async fn node_get_capabilities(
@@ -347,7 +664,12 @@ impl Node for RLVMNode {
         _request: Request<NodeGetCapabilitiesRequest>,
     ) -> Result<Response<NodeGetCapabilitiesResponse>, Status> {
         let reply = NodeGetCapabilitiesResponse {
-            capabilities: vec![node_capability!(StageUnstageVolume)],
+            capabilities: vec![
+                node_capability!(StageUnstageVolume),
+                node_capability!(ExpandVolume),
+                node_capability!(GetVolumeStats),
+                node_capability!(VolumeCondition),
+            ],
         };
 
         Ok(Response::new(reply))
@@ -358,9 +680,7 @@ impl Node for RLVMNode {
         _request: Request<NodeGetInfoRequest>,
     ) -> Result<Response<NodeGetInfoResponse>, Status> {
         let reply = NodeGetInfoResponse {
-            accessible_topology: Some(Topology {
-                segments: HashMap::from([("host".into(), self.node_id.to_string())]),
-            }),
+            accessible_topology: Some(self.topology.clone()),
 
             // TODO: Do we want this to be some multiple of the smallest allowed size?
             max_volumes_per_node: 0,
@@ -370,3 +690,45 @@ impl Node for RLVMNode {
         Ok(Response::new(reply))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::csi::v1_7_0::volume_capability::{BlockVolume, MountVolume};
+
+    use super::*;
+
+    #[test]
+    fn detects_filesystem_to_grow() {
+        let dir = std::env::temp_dir().join(format!("rlvm-{}", Uuid::new_v4()));
+        std::fs::create_dir(&dir).unwrap();
+
+        // Raw block volumes are bind mounted onto a regular file
+        let file = dir.join("block");
+        std::fs::write(&file, "").unwrap();
+
+        let capability = |access_type| VolumeCapability {
+            access_type: Some(access_type),
+            ..Default::default()
+        };
+        let block = capability(AccessType::Block(BlockVolume {}));
+        let mount = capability(AccessType::Mount(MountVolume::default()));
+
+        for (capability, volume_path, expected) in [
+            (None, &dir, true),
+            (None, &file, false),
+            // The capability wins over the volume path
+            (Some(&block), &dir, false),
+            (Some(&mount), &file, true),
+        ] {
+            assert_eq!(
+                has_filesystem(capability, volume_path),
+                expected,
+                "{:?} {:?}",
+                capability,
+                volume_path
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
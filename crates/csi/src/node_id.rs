@@ -0,0 +1,262 @@
+//! Persistent identity of a node.
+//!
+//! The node ID ends up in the host topology segment of every volume, so it
+//! must survive restarts of the plugin. Otherwise, the node affinity of all
+//! existing volumes would stop matching.
+
+use std::{
+    fs,
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use uuid::{Builder, Uuid};
+
+/// Default location of the file persisting the node ID
+pub const DEFAULT_STATE_FILE: &str = "/var/lib/rlvm/node-id";
+
+/// Location of the machine ID set up by systemd / dbus
+const MACHINE_ID_FILE: &str = "/etc/machine-id";
+
+/// ID of the application, which node IDs derived from the machine ID are specific to
+const APP_ID: Uuid = Uuid::from_u128(0xcba2b6d7_e477_4a6f_8211_42d4ba7ec826);
+
+/// Resolve the ID of the current node.
+///
+/// In order of preference, the ID is taken from:
+/// 1. `explicit`, if provided (e.g. through the command line)
+/// 2. The ID previously persisted in `state_file`
+/// 3. An ID derived from the machine ID of the host (`/etc/machine-id`)
+/// 4. A freshly generated random ID
+///
+/// The resolved ID is persisted to `state_file`, so that later starts resolve
+/// to the same ID. An explicit ID which conflicts with the persisted one is
+/// refused, since it would orphan every volume created under the old ID.
+pub fn resolve(explicit: Option<Uuid>, state_file: &Path) -> Result<Uuid, Error> {
+    resolve_with(explicit, state_file, Path::new(MACHINE_ID_FILE))
+}
+
+/// Resolve the ID of the current node, using the machine ID found at `machine_id_file`.
+///
+/// See [resolve] for more info.
+fn resolve_with(
+    explicit: Option<Uuid>,
+    state_file: &Path,
+    machine_id_file: &Path,
+) -> Result<Uuid, Error> {
+    let persisted = read_id(state_file)?;
+
+    let node_id = match (explicit, persisted) {
+        (Some(explicit), Some(persisted)) if explicit != persisted => {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "node ID `{}` conflicts with the ID `{}` persisted in `{}`: remove the file to change the ID of this node",
+                    explicit,
+                    persisted,
+                    state_file.to_string_lossy(),
+                ),
+            ));
+        }
+        (Some(explicit), _) => explicit,
+        (None, Some(persisted)) => persisted,
+        (None, None) => match read_id(machine_id_file)? {
+            Some(machine_id) => app_specific_id(machine_id),
+            None => {
+                log::warn!(
+                    "no machine ID found at `{}`, generating a random node ID",
+                    machine_id_file.to_string_lossy()
+                );
+
+                Uuid::new_v4()
+            }
+        },
+    };
+
+    if persisted.is_none() {
+        persist_id(node_id, state_file)?;
+    }
+
+    Ok(node_id)
+}
+
+/// Derive an ID specific to this application from the machine ID, the same way as
+/// `sd_id128_get_machine_app_specific` does.
+///
+/// Note: The machine ID must not be exposed, but the node ID is published in the
+/// topology of every volume.
+fn app_specific_id(machine_id: Uuid) -> Uuid {
+    let mut mac = Hmac::<Sha256>::new_from_slice(machine_id.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(APP_ID.as_bytes());
+
+    let mut bytes = [0; 16];
+    bytes.copy_from_slice(&mac.finalize().into_bytes()[..16]);
+
+    Builder::from_random_bytes(bytes).into_uuid()
+}
+
+/// Read an ID from a file, if it exists
+fn read_id(path: &Path) -> Result<Option<Uuid>, Error> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+
+    // Treat an empty or uninitialized file as missing. systemd writes `uninitialized`
+    //  to the machine ID until the first boot has completed.
+    let contents = contents.trim();
+    if contents.is_empty() || contents == "uninitialized" {
+        return Ok(None);
+    }
+
+    Uuid::parse_str(contents).map(Some).map_err(|err| {
+        Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "invalid node ID `{}` in `{}`: {}",
+                contents,
+                path.to_string_lossy(),
+                err
+            ),
+        )
+    })
+}
+
+/// Atomically write an ID to a file, creating its parent directories as needed
+fn persist_id(node_id: Uuid, path: &Path) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, format!("{}\n", node_id))?;
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    const MACHINE_ID: &str = "0b6e1c2a4f5d4c3b9a8e7d6f5e4c3b2a";
+
+    /// Create an empty directory to hold the files of a single test
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rlvm-{}", Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        dir
+    }
+
+    // Node IDs end up in the topology of existing volumes, so they must never change
+    #[test]
+    fn derive_app_specific_id() {
+        let machine_id = Uuid::parse_str("0b6e1c2a4f5d4c3b9a8e7d6f5e4c3b2a").unwrap();
+
+        assert_eq!(
+            app_specific_id(machine_id),
+            Uuid::parse_str("6458b30b-5277-457f-8400-af3eb5e6192a").unwrap()
+        );
+    }
+
+    #[test]
+    fn refuse_conflicting_explicit_id() {
+        let dir = temp_dir();
+        let state_file = dir.join("node-id");
+
+        let persisted = Uuid::new_v4();
+        persist_id(persisted, &state_file).unwrap();
+
+        let err =
+            resolve_with(Some(Uuid::new_v4()), &state_file, &dir.join("machine-id")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+        // The persisted ID is left alone
+        assert_eq!(read_id(&state_file).unwrap(), Some(persisted));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_in_order_of_preference() {
+        let explicit = Uuid::new_v4();
+        let persisted = Uuid::new_v4();
+        let derived = app_specific_id(Uuid::parse_str(MACHINE_ID).unwrap());
+
+        for (explicit, persisted, expected) in [
+            (Some(explicit), None, explicit),
+            (Some(persisted), Some(persisted), persisted),
+            (None, Some(persisted), persisted),
+            (None, None, derived),
+        ] {
+            let dir = temp_dir();
+            let state_file = dir.join("node-id");
+            let machine_id_file = dir.join("machine-id");
+
+            fs::write(&machine_id_file, format!("{}\n", MACHINE_ID)).unwrap();
+            if let Some(persisted) = persisted {
+                persist_id(persisted, &state_file).unwrap();
+            }
+
+            assert_eq!(
+                resolve_with(explicit, &state_file, &machine_id_file).unwrap(),
+                expected,
+                "{:?} {:?}",
+                explicit,
+                persisted
+            );
+
+            // Later starts resolve to the same ID
+            assert_eq!(read_id(&state_file).unwrap(), Some(expected));
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn persist_id_round_trip() {
+        let dir = temp_dir();
+        let state_file = dir.join("state").join("node-id");
+        let node_id = Uuid::new_v4();
+
+        // Missing parent directories are created
+        persist_id(node_id, &state_file).unwrap();
+        assert_eq!(read_id(&state_file).unwrap(), Some(node_id));
+        assert!(!state_file.with_extension("tmp").exists());
+
+        // Overwriting replaces the ID
+        let node_id = Uuid::new_v4();
+        persist_id(node_id, &state_file).unwrap();
+        assert_eq!(read_id(&state_file).unwrap(), Some(node_id));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fall_back_to_random_id_without_machine_id() {
+        for machine_id in [None, Some(""), Some("\n"), Some("uninitialized\n")] {
+            let dir = temp_dir();
+            let state_file = dir.join("node-id");
+            let machine_id_file = dir.join("machine-id");
+
+            if let Some(machine_id) = machine_id {
+                fs::write(&machine_id_file, machine_id).unwrap();
+            }
+
+            let node_id = resolve_with(None, &state_file, &machine_id_file).unwrap();
+            assert_eq!(
+                resolve_with(None, &state_file, &machine_id_file).unwrap(),
+                node_id,
+                "{:?}",
+                machine_id
+            );
+
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+}
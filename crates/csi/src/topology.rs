@@ -0,0 +1,194 @@
+//! Topology of the nodes which volumes are accessible from.
+//!
+//! Volumes are local to the node they were created on, so the topology of a node
+//! consists of a segment identifying the node itself, along with any number of
+//! configured segments for the larger failure domains it belongs to (e.g. its rack
+//! or zone).
+
+use std::{collections::HashMap, str::FromStr};
+
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::csi::v1_7_0::{Topology, TopologyRequirement};
+
+/// Key of the topology segment identifying a node, unless configured otherwise
+pub const DEFAULT_HOST_KEY: &str = "host";
+
+/// A topology segment, as passed on the command line (`KEY=VALUE`)
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for Segment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !value.is_empty() => Ok(Segment {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(format!(
+                "invalid topology segment `{}`: expected `KEY=VALUE`",
+                s
+            )),
+        }
+    }
+}
+
+/// Build the topology of a node from its ID and additional segments
+pub fn node_topology(node_id: Uuid, host_key: &str, segments: &[Segment]) -> Topology {
+    let mut topology = HashMap::from([(host_key.to_string(), node_id.to_string())]);
+    topology.extend(
+        segments
+            .iter()
+            .map(|segment| (segment.key.clone(), segment.value.clone())),
+    );
+
+    Topology { segments: topology }
+}
+
+/// Whether the (possibly partial) `topology` covers the node, i.e. all of its
+/// segments match those of the node
+pub fn matches(node: &Topology, topology: &Topology) -> bool {
+    topology
+        .segments
+        .iter()
+        .all(|(key, value)| node.segments.get(key) == Some(value))
+}
+
+/// Check that volumes of the node can satisfy the accessibility requirements of a
+/// CreateVolume call.
+///
+/// Fails with INVALID_ARGUMENT if the requirements are malformed, and with
+/// RESOURCE_EXHAUSTED if the node is not among the requisite topologies. Topologies
+/// with keys unknown to the node never match it.
+pub fn check_requirements(
+    node: &Topology,
+    requirements: &TopologyRequirement,
+) -> Result<(), Status> {
+    // Preferred topologies must be a subset of the requisite ones, if any
+    if !requirements.requisite.is_empty() {
+        if let Some(topology) = requirements
+            .preferred
+            .iter()
+            .find(|topology| !requirements.requisite.contains(topology))
+        {
+            return Err(Status::invalid_argument(format!(
+                "preferred topology {:?} is not among the requisite topologies",
+                topology.segments
+            )));
+        }
+    }
+
+    // Preferences alone do not restrict where the volume may be placed
+    if !requirements.requisite.is_empty()
+        && !requirements
+            .requisite
+            .iter()
+            .any(|topology| matches(node, topology))
+    {
+        return Err(Status::resource_exhausted(format!(
+            "volumes of this node ({:?}) cannot be accessed from any of the requisite topologies",
+            node.segments
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn topology(segments: &[(&str, &str)]) -> Topology {
+        Topology {
+            segments: segments
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    fn node() -> Topology {
+        topology(&[("host", "node-a"), ("zone", "zone-1")])
+    }
+
+    #[test]
+    fn matches_subset_of_node() {
+        assert!(matches(&node(), &topology(&[])));
+        assert!(matches(&node(), &topology(&[("zone", "zone-1")])));
+        assert!(matches(&node(), &node()));
+    }
+
+    #[test]
+    fn does_not_match_other_values_or_keys() {
+        assert!(!matches(&node(), &topology(&[("zone", "zone-2")])));
+        assert!(!matches(&node(), &topology(&[("rack", "rack-1")])));
+        assert!(!matches(
+            &node(),
+            &topology(&[("host", "node-a"), ("zone", "zone-2")])
+        ));
+    }
+
+    #[test]
+    fn accepts_matching_requisite() {
+        let requirements = TopologyRequirement {
+            requisite: vec![
+                topology(&[("zone", "zone-2")]),
+                topology(&[("zone", "zone-1")]),
+            ],
+            preferred: vec![topology(&[("zone", "zone-1")])],
+        };
+
+        assert!(check_requirements(&node(), &requirements).is_ok());
+    }
+
+    #[test]
+    fn accepts_preferences_alone() {
+        let requirements = TopologyRequirement {
+            requisite: vec![],
+            preferred: vec![topology(&[("zone", "zone-2")])],
+        };
+
+        assert!(check_requirements(&node(), &requirements).is_ok());
+    }
+
+    #[test]
+    fn rejects_unmatched_requisite() {
+        let requirements = TopologyRequirement {
+            requisite: vec![topology(&[("zone", "zone-2")])],
+            preferred: vec![],
+        };
+
+        let status = check_requirements(&node(), &requirements).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn unknown_keys_do_not_match() {
+        let requirements = TopologyRequirement {
+            requisite: vec![topology(&[("rack", "rack-1")])],
+            preferred: vec![],
+        };
+
+        let status = check_requirements(&node(), &requirements).unwrap_err();
+        assert_eq!(status.code(), Code::ResourceExhausted);
+    }
+
+    #[test]
+    fn rejects_preferred_outside_requisite() {
+        let requirements = TopologyRequirement {
+            requisite: vec![topology(&[("zone", "zone-1")])],
+            preferred: vec![topology(&[("zone", "zone-2")])],
+        };
+
+        let status = check_requirements(&node(), &requirements).unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+    }
+}
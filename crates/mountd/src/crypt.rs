@@ -0,0 +1,158 @@
+//! Helpers for managing the dm-crypt mappings of encrypted (LUKS) volumes.
+
+use std::{
+    ffi::OsStr,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tonic::Status;
+
+/// Directory holding the device nodes of device mapper devices
+const MAPPER_DIR: &str = "/dev/mapper";
+
+/// Prefix of the names of the dm-crypt mappings managed by mountd
+pub const MAPPING_PREFIX: &str = "rlvm-";
+
+/// The name of the dm-crypt mapping for the volume with the specified UUID
+pub fn mapping_name(uuid: &str) -> String {
+    format!("{}{}", MAPPING_PREFIX, uuid)
+}
+
+/// The path of the dm-crypt mapping for the volume with the specified UUID
+pub fn mapping_path(uuid: &str) -> PathBuf {
+    Path::new(MAPPER_DIR).join(mapping_name(uuid))
+}
+
+/// Open the dm-crypt mapping of the encrypted `device` with the specified UUID,
+/// returning the path to the decrypted device.
+///
+/// Note: This is a no-op if the mapping is already open.
+pub fn open(device: &Path, uuid: &str, passphrase: &str) -> Result<PathBuf, Status> {
+    let path = mapping_path(uuid);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    if passphrase.is_empty() {
+        return Err(Status::failed_precondition(format!(
+            "encrypted volume `{}` is not open and no passphrase was supplied",
+            uuid
+        )));
+    }
+
+    cryptsetup(
+        [
+            "open".as_ref(),
+            "--type".as_ref(),
+            "luks".as_ref(),
+            "--key-file".as_ref(),
+            "-".as_ref(),
+            device.as_os_str(),
+            mapping_name(uuid).as_ref(),
+        ],
+        Some(passphrase),
+    )?;
+
+    log::info!(
+        "opened encrypted device `{}` at `{}`",
+        device.to_string_lossy(),
+        path.to_string_lossy()
+    );
+
+    Ok(path)
+}
+
+/// Close the dm-crypt mapping of the volume with the specified UUID.
+///
+/// Note: This is a no-op if the mapping is not open.
+pub fn close(uuid: &str) -> Result<(), Status> {
+    if !mapping_path(uuid).exists() {
+        return Ok(());
+    }
+
+    cryptsetup(["close".as_ref(), mapping_name(uuid).as_ref()], None)?;
+
+    log::info!("closed encrypted device for volume `{}`", uuid);
+
+    Ok(())
+}
+
+/// Grow the dm-crypt mapping at `path` to fill its underlying device, if it is one
+/// of the mappings managed by mountd.
+///
+/// Note: LUKS2 keeps the volume key in the kernel keyring, so resizing its mappings
+/// requires the passphrase.
+pub fn resize(path: &Path, passphrase: &str) -> Result<(), Status> {
+    let name = match path.strip_prefix(MAPPER_DIR).ok().and_then(Path::to_str) {
+        Some(name) if name.starts_with(MAPPING_PREFIX) => name,
+        _ => return Ok(()),
+    };
+
+    cryptsetup(resize_args(name, passphrase), Some(passphrase))
+}
+
+/// Arguments to `cryptsetup` for growing the mapping `name`, reading the passphrase
+/// (if any) from stdin
+fn resize_args<'a>(name: &'a str, passphrase: &str) -> Vec<&'a OsStr> {
+    let mut args = vec![OsStr::new("resize")];
+    if !passphrase.is_empty() {
+        args.extend(["--key-file", "-"].map(OsStr::new));
+    }
+    args.push(OsStr::new(name));
+
+    args
+}
+
+/// Run a cryptsetup command, optionally feeding it a passphrase through stdin
+fn cryptsetup<'a, I>(args: I, passphrase: Option<&str>) -> Result<(), Status>
+where
+    I: IntoIterator<Item = &'a OsStr>,
+{
+    let mut child = Command::new("cryptsetup")
+        .args(args)
+        .arg("--batch-mode")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Status::internal(format!("could not run cryptsetup command: {}", err)))?;
+
+    // Dropping stdin closes it, so that cryptsetup does not wait for more input
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin
+            .write_all(passphrase.unwrap_or_default().as_bytes())
+            .map_err(|err| {
+                Status::internal(format!("could not pass passphrase to cryptsetup: {}", err))
+            })?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| Status::internal(format!("could not run cryptsetup command: {}", err)))?;
+
+    // Print out the stderr if the command failed
+    if !output.status.success() {
+        return Err(Status::internal(format!(
+            "cryptsetup command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resize_reads_passphrase_from_stdin() {
+        assert_eq!(
+            resize_args("rlvm-uuid", "secret"),
+            ["resize", "--key-file", "-", "rlvm-uuid"]
+        );
+        assert_eq!(resize_args("rlvm-uuid", ""), ["resize", "rlvm-uuid"]);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod crypt;
+pub mod mounts;
 pub mod server;
 
 use std::{os::unix::fs::MetadataExt, path::Path};
@@ -19,6 +21,9 @@ pub struct Config {
     for_group: Group,
 
     /// Whitelist of globs that may be owned by a different user / group pair for mounting
+    ///
+    /// Note: This needs to include the devices of every volume group managed by volumed
+    /// (e.g. `/dev/<vg>/*`), since they are owned by root.
     whitelist: Vec<String>,
 }
 
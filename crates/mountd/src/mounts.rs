@@ -0,0 +1,49 @@
+//! Helpers for inspecting the mount table of the system.
+
+use std::path::{Path, PathBuf};
+
+use common::unescape;
+
+/// Location of the mount table for the current mount namespace
+const MOUNT_TABLE: &str = "/proc/self/mounts";
+
+/// Represents a single entry of the mount table
+#[derive(Clone, Debug)]
+pub struct MountEntry {
+    /// The mounted device (or pseudo-device)
+    pub source: PathBuf,
+
+    /// Where the device is mounted
+    pub target: PathBuf,
+
+    /// The type of the mounted filesystem
+    pub fs_type: String,
+}
+
+/// Read all entries of the mount table
+pub fn mount_table() -> Result<Vec<MountEntry>, std::io::Error> {
+    let table = std::fs::read_to_string(MOUNT_TABLE)?;
+
+    Ok(table
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+
+            Some(MountEntry {
+                source: unescape(columns.next()?),
+                target: unescape(columns.next()?),
+                fs_type: columns.next()?.to_string(),
+            })
+        })
+        .collect())
+}
+
+/// Find the entry of the mount table for the specified mountpoint.
+///
+/// Note: If the path has been mounted over multiple times, the topmost mount
+/// (which is the only visible one) is returned.
+pub fn find_mount(target: &Path) -> Result<Option<MountEntry>, std::io::Error> {
+    Ok(mount_table()?
+        .into_iter()
+        .rfind(|entry| entry.target == target))
+}
@@ -1,17 +1,22 @@
-use std::path::Path;
+use std::{os::unix::fs::FileTypeExt, path::Path};
 
+use common::{DEFAULT_FS_TYPE, SUPPORTED_FS_TYPES};
 use lvm2_cmd::{error::LVMError, lv::LogicalVolume, InvalidResourceUUIDError, ResourceSelector};
 use mountpoints::mountpaths;
-use nix::unistd::chown;
+use nix::{errno::Errno, sys::statvfs::statvfs, unistd::chown};
 use sys_mount::{unmount, Mount, MountFlags, UnmountFlags};
 use tonic::{Request, Response, Status};
 
 use crate::{
+    crypt,
+    mounts::find_mount,
     spec::{
         mount_service_server::{MountService, MountServiceServer},
-        BlockDevice, GetLvmBlockPathRequest,
+        BlockDevice, CloseEncryptedDeviceRequest, Empty, FilesystemCondition, FilesystemStats,
+        GetFilesystemStatsRequest, GetLvmBlockPathRequest, GrowFilesystemRequest,
+        GrowFilesystemResponse,
         MountFlag::{self, ReadOnly},
-        MountRequest, MountResponse, UnmountRequest, UnmountResponse,
+        MountRequest, MountResponse, OpenEncryptedDeviceRequest, UnmountRequest, UnmountResponse,
     },
     Config,
 };
@@ -37,18 +42,11 @@ impl MountService for MountdServer {
         request: Request<GetLvmBlockPathRequest>,
     ) -> Result<Response<BlockDevice>, Status> {
         let req = request.into_inner();
-        let uuid = req
-            .uuid
-            .try_into()
-            .map_err(|err: InvalidResourceUUIDError| Status::invalid_argument(err.to_string()))?;
-
-        let lv = LogicalVolume::from_uuid(&uuid).map_err(|err| match err {
-            LVMError::NotFound { .. } => Status::not_found(err.to_string()),
-            _ => Status::internal(err.to_string()),
-        })?;
+        let lv = find_lv(req.uuid)?;
 
         Ok(Response::new(BlockDevice {
             path: lv.path.to_string_lossy().to_string(),
+            capacity_bytes: (*lv.capacity_bytes).try_into().unwrap_or_default(),
         }))
     }
 
@@ -91,7 +89,10 @@ impl MountService for MountdServer {
             ))
         })?;
         self.config
-            .ensure_interactable(dst, req.flags.contains(&ReadOnly.into()))
+            .ensure_interactable(
+                dst,
+                req.flags.contains(&ReadOnly.into()) || req.options.iter().any(|opt| opt == "ro"),
+            )
             .map_err(|err| {
                 Status::permission_denied(format!(
                     "specified destination `{}` cannot be mounted by current config: {}",
@@ -117,6 +118,20 @@ impl MountService for MountdServer {
             return Ok(Response::new(MountResponse {}));
         }
 
+        // Only allow known filesystems, defaulting to XFS for older clients
+        let fs_type = if req.fs_type.is_empty() {
+            DEFAULT_FS_TYPE
+        } else {
+            req.fs_type.as_str()
+        };
+
+        if !SUPPORTED_FS_TYPES.contains(&fs_type) {
+            return Err(Status::invalid_argument(format!(
+                "unsupported filesystem `{}`: must be one of {:?}",
+                fs_type, SUPPORTED_FS_TYPES
+            )));
+        }
+
         // Gather the mount flags
         let mapped: Result<Vec<_>, _> = req
             .flags
@@ -128,6 +143,10 @@ impl MountService for MountdServer {
 
         let mut flags = MountFlags::from_iter(mapped?.into_iter().map(MountFlag::into));
 
+        // Split the extra options into flags and filesystem specific data
+        let (option_flags, data) = parse_options(&req.options)?;
+        flags.insert(option_flags);
+
         // Always apply a few options for security
         // NODEV means that any nested block devices will not be mounted
         // NOSUID means that any SUID executable will be mounted without the SUID flag
@@ -138,8 +157,9 @@ impl MountService for MountdServer {
 
         // Mount the request
         let result = Mount::builder()
-            .fstype("xfs")
+            .fstype(fs_type)
             .flags(flags)
+            .data(&data)
             .mount(src, dst)
             .map_err(|err| {
                 Status::internal(format!("could not mount request: {}", err.to_string()))
@@ -191,9 +211,21 @@ impl MountService for MountdServer {
             return Ok(Response::new(UnmountResponse {}));
         }
 
+        // A bind mounted block device takes on the ownership of the device itself,
+        //  so check the directory containing it instead
+        let is_block_device = mountpoint
+            .metadata()
+            .map(|meta| meta.file_type().is_block_device())
+            .unwrap_or_default();
+        let owner = if is_block_device {
+            mountpoint.parent().unwrap_or(mountpoint)
+        } else {
+            mountpoint
+        };
+
         // Make sure that we can interact with the endpoint
         self.config
-            .ensure_interactable(mountpoint, false)
+            .ensure_interactable(owner, false)
             .map_err(|err| {
                 Status::permission_denied(format!(
                     "specified mountpoint `{}` cannot be unmounted by current config: {}",
@@ -211,6 +243,317 @@ impl MountService for MountdServer {
 
         Ok(Response::new(UnmountResponse {}))
     }
+
+    async fn mount_block_device(
+        &self,
+        request: Request<MountRequest>,
+    ) -> Result<Response<MountResponse>, Status> {
+        let req = request.into_inner();
+        let mounts = mountpaths()
+            .map_err(|err| Status::internal(format!("could not get mountpoints: {}", err)))?;
+
+        log::info!("got mount block device request: {:?}", req);
+
+        let mount = req
+            .mount
+            .ok_or(Status::invalid_argument("missing required `mount` arg"))?;
+
+        // Verify that we got a dev / dest
+        if mount.src.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `src` in mount",
+            ));
+        }
+        if mount.dst.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `dst` in mount",
+            ));
+        }
+
+        // Make sure that both paths are allowed
+        let src = Path::new(&mount.src);
+        let dst = Path::new(&mount.dst);
+
+        self.config.ensure_interactable(src, false).map_err(|err| {
+            Status::permission_denied(format!(
+                "specified source `{}` cannot be mounted by current config: {}",
+                src.to_string_lossy(),
+                err
+            ))
+        })?;
+
+        // Short out if the endpoint is already mounted
+        if mounts.contains(&dst.into()) {
+            log::info!(
+                "skipping specified mountpoint, as it is already mounted: {}",
+                dst.to_string_lossy()
+            );
+            return Ok(Response::new(MountResponse {}));
+        }
+
+        self.config
+            .ensure_interactable(dst, req.flags.contains(&ReadOnly.into()))
+            .map_err(|err| {
+                Status::permission_denied(format!(
+                    "specified destination `{}` cannot be mounted by current config: {}",
+                    dst.to_string_lossy(),
+                    err
+                ))
+            })?;
+
+        // Make sure that we are binding an actual block device onto a plain file
+        let is_block_device = src
+            .metadata()
+            .map(|meta| meta.file_type().is_block_device())
+            .unwrap_or_default();
+        if !is_block_device {
+            return Err(Status::failed_precondition(format!(
+                "mount src is not a block device: {}",
+                src.to_string_lossy(),
+            )));
+        }
+        if !dst.is_file() {
+            return Err(Status::failed_precondition(format!(
+                "mount dst is not a file: {}",
+                dst.to_string_lossy(),
+            )));
+        }
+
+        // Gather the mount flags
+        let mapped: Result<Vec<_>, _> = req
+            .flags
+            .into_iter()
+            .map(|flag| {
+                MountFlag::from_i32(flag).ok_or(Status::invalid_argument("invalid mount flag"))
+            })
+            .collect();
+
+        let mut flags = MountFlags::from_iter(mapped?.into_iter().map(MountFlag::into));
+
+        // Block devices are always bind mounted, and NODEV cannot be applied here since
+        //  it would make the device itself unusable
+        flags.insert(MountFlags::from_iter([
+            MountFlags::BIND,
+            MountFlags::NOSUID,
+        ]));
+
+        // Mount the request
+        let result = Mount::builder()
+            .flags(flags)
+            .mount(src, dst)
+            .map_err(|err| Status::internal(format!("could not mount request: {}", err)))?;
+
+        log::info!("mounted block device with flags {:?}: {:?}", flags, result);
+
+        Ok(Response::new(MountResponse {}))
+    }
+
+    async fn grow_filesystem(
+        &self,
+        request: Request<GrowFilesystemRequest>,
+    ) -> Result<Response<GrowFilesystemResponse>, Status> {
+        let req = request.into_inner();
+        let mounts = mountpaths()
+            .map_err(|err| Status::internal(format!("could not get mountpoints: {}", err)))?;
+
+        log::info!("got grow filesystem request for `{}`", req.path);
+
+        // Verify that we got a path
+        if req.path.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `path` in grow filesystem",
+            ));
+        }
+
+        let mountpoint = Path::new(&req.path);
+
+        // Filesystems can only be grown while mounted
+        if !mounts.contains(&mountpoint.into()) {
+            return Err(Status::failed_precondition(format!(
+                "specified path is not mounted: {}",
+                mountpoint.to_string_lossy()
+            )));
+        }
+
+        // Make sure that we can interact with the endpoint
+        self.config
+            .ensure_interactable(mountpoint, false)
+            .map_err(|err| {
+                Status::permission_denied(format!(
+                    "specified mountpoint `{}` cannot be grown by current config: {}",
+                    mountpoint.to_string_lossy(),
+                    err
+                ))
+            })?;
+
+        let mount = find_mount(mountpoint)
+            .map_err(|err| Status::internal(format!("could not read mount table: {}", err)))?
+            .ok_or(Status::failed_precondition(format!(
+                "specified path is not in the mount table: {}",
+                mountpoint.to_string_lossy()
+            )))?;
+
+        // Encrypted devices need to be grown before their filesystem
+        crypt::resize(&mount.source, &req.passphrase)?;
+
+        // Grow the filesystem to fill the device
+        let mut cmd = match mount.fs_type.as_str() {
+            "xfs" => {
+                let mut cmd = std::process::Command::new("xfs_growfs");
+                cmd.arg(mountpoint);
+                cmd
+            }
+            "ext4" => {
+                let mut cmd = std::process::Command::new("resize2fs");
+                cmd.arg(&mount.source);
+                cmd
+            }
+            "btrfs" => {
+                let mut cmd = std::process::Command::new("btrfs");
+                cmd.args(["filesystem", "resize", "max"]).arg(mountpoint);
+                cmd
+            }
+            other => {
+                return Err(Status::failed_precondition(format!(
+                    "cannot grow unsupported filesystem `{}` at {}",
+                    other,
+                    mountpoint.to_string_lossy()
+                )))
+            }
+        };
+
+        let cmd = cmd.output().map_err(|err| {
+            Status::internal(format!(
+                "could not run grow command for {}: {}",
+                mount.fs_type, err
+            ))
+        })?;
+
+        // Print out the stderr if the command failed
+        if !cmd.status.success() {
+            return Err(Status::internal(format!(
+                "could not grow filesystem at `{}`: {}",
+                mountpoint.to_string_lossy(),
+                String::from_utf8_lossy(&cmd.stderr)
+            )));
+        }
+
+        log::info!("grew filesystem at {}", mountpoint.to_string_lossy());
+
+        Ok(Response::new(GrowFilesystemResponse {}))
+    }
+
+    async fn get_filesystem_stats(
+        &self,
+        request: Request<GetFilesystemStatsRequest>,
+    ) -> Result<Response<FilesystemStats>, Status> {
+        let req = request.into_inner();
+        let mounts = mountpaths()
+            .map_err(|err| Status::internal(format!("could not get mountpoints: {}", err)))?;
+
+        // Verify that we got a path
+        if req.path.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `path` in get filesystem stats",
+            ));
+        }
+
+        let mountpoint = Path::new(&req.path);
+
+        // Stats only make sense for a mounted filesystem
+        if !mounts.contains(&mountpoint.into()) {
+            return Err(Status::not_found(format!(
+                "specified path is not mounted: {}",
+                mountpoint.to_string_lossy()
+            )));
+        }
+
+        let stats = match statvfs(mountpoint) {
+            Ok(stats) => stats,
+
+            // XFS (amongst others) returns EIO for every call once it has shut down
+            Err(Errno::EIO) => {
+                return Ok(Response::new(FilesystemStats {
+                    condition: Some(FilesystemCondition {
+                        abnormal: true,
+                        message: format!(
+                            "filesystem at `{}` returned an I/O error: has it shut down?",
+                            mountpoint.to_string_lossy()
+                        ),
+                    }),
+                    ..Default::default()
+                }));
+            }
+            Err(err) => {
+                return Err(Status::internal(format!(
+                    "could not stat filesystem at `{}`: {}",
+                    mountpoint.to_string_lossy(),
+                    err
+                )))
+            }
+        };
+
+        let block_size = stats.fragment_size() as u64;
+        let blocks = stats.blocks() as u64;
+        let files = stats.files() as u64;
+
+        Ok(Response::new(FilesystemStats {
+            total_bytes: blocks * block_size,
+            available_bytes: stats.blocks_available() as u64 * block_size,
+            used_bytes: blocks.saturating_sub(stats.blocks_free() as u64) * block_size,
+
+            total_inodes: files,
+            available_inodes: stats.files_free() as u64,
+            used_inodes: files.saturating_sub(stats.files_free() as u64),
+
+            condition: Some(FilesystemCondition {
+                abnormal: false,
+                message: "filesystem is healthy".into(),
+            }),
+        }))
+    }
+
+    async fn open_encrypted_device(
+        &self,
+        request: Request<OpenEncryptedDeviceRequest>,
+    ) -> Result<Response<BlockDevice>, Status> {
+        let req = request.into_inner();
+
+        log::info!("got open encrypted device request for `{}`", req.uuid);
+
+        let lv = find_lv(req.uuid.clone())?;
+        let path = crypt::open(&lv.path, &req.uuid, &req.passphrase)?;
+
+        Ok(Response::new(BlockDevice {
+            path: path.to_string_lossy().to_string(),
+            capacity_bytes: (*lv.capacity_bytes).try_into().unwrap_or_default(),
+        }))
+    }
+
+    async fn close_encrypted_device(
+        &self,
+        request: Request<CloseEncryptedDeviceRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let req = request.into_inner();
+
+        log::info!("got close encrypted device request: {:?}", req);
+
+        // Validate the UUID, so that it cannot be used to close arbitrary mappings
+        let is_uuid = req
+            .uuid
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+        if req.uuid.is_empty() || !is_uuid {
+            return Err(Status::invalid_argument(format!(
+                "invalid volume uuid `{}`",
+                req.uuid
+            )));
+        }
+
+        crypt::close(&req.uuid)?;
+
+        Ok(Response::new(Empty {}))
+    }
 }
 
 impl From<MountFlag> for MountFlags {
@@ -222,3 +565,48 @@ impl From<MountFlag> for MountFlags {
         }
     }
 }
+
+/// Find the [LogicalVolume] with the specified UUID
+fn find_lv(uuid: String) -> Result<LogicalVolume, Status> {
+    let uuid = uuid
+        .try_into()
+        .map_err(|err: InvalidResourceUUIDError| Status::invalid_argument(err.to_string()))?;
+
+    LogicalVolume::from_uuid(&uuid).map_err(|err| match err {
+        LVMError::NotFound { .. } => Status::not_found(err.to_string()),
+        _ => Status::internal(err.to_string()),
+    })
+}
+
+/// Split generic mount options into their flags, leaving the rest as filesystem data.
+///
+/// Options that would undo the flags applied for security are refused.
+fn parse_options(options: &[String]) -> Result<(MountFlags, String), Status> {
+    let mut flags = MountFlags::empty();
+    let mut data = Vec::new();
+
+    for option in options
+        .iter()
+        .map(|opt| opt.trim())
+        .filter(|opt| !opt.is_empty())
+    {
+        match option {
+            "ro" => flags.insert(MountFlags::RDONLY),
+            "noexec" => flags.insert(MountFlags::NOEXEC),
+            "noatime" => flags.insert(MountFlags::NOATIME),
+            "nodiratime" => flags.insert(MountFlags::NODIRATIME),
+            "relatime" => flags.insert(MountFlags::RELATIME),
+            "sync" => flags.insert(MountFlags::SYNCHRONOUS),
+            "rw" | "exec" | "nodev" | "nosuid" => {}
+            "dev" | "suid" => {
+                return Err(Status::invalid_argument(format!(
+                    "mount option `{}` is not allowed",
+                    option
+                )))
+            }
+            other => data.push(other),
+        }
+    }
+
+    Ok((flags, data.join(",")))
+}
@@ -0,0 +1,126 @@
+//! Authentication of the clients reaching volumed over the network.
+//!
+//! Clients authenticate with a token shared with volumed, which they send as a
+//! bearer token in the `authorization` metadata of every request. The Unix socket
+//! is protected by its file permissions instead, so it does not need a token.
+//!
+//! Since the token (as well as the passphrases of encrypted volumes) would otherwise
+//! cross the network in plaintext, TCP connections use TLS unless they stay on the
+//! loopback interface.
+
+use std::path::Path;
+
+use tonic::{
+    metadata::AsciiMetadataValue,
+    service::{interceptor::InterceptedService, Interceptor},
+    transport::{Certificate, Channel, ClientTlsConfig, Identity, ServerTlsConfig},
+    Request, Status,
+};
+
+use crate::spec::volume_service_client::VolumeServiceClient;
+
+/// Metadata key holding the token of a request
+const AUTHORIZATION_KEY: &str = "authorization";
+
+/// A client of volumed, which passes along the shared token (if any)
+pub type Client = VolumeServiceClient<InterceptedService<Channel, WithToken>>;
+
+/// Read the shared token from a file, ignoring any surrounding whitespace
+pub fn read_token(path: &Path) -> Result<String, String> {
+    let token = std::fs::read_to_string(path).map_err(|err| {
+        format!(
+            "could not read token file {}: {}",
+            path.to_string_lossy(),
+            err
+        )
+    })?;
+
+    let token = token.trim();
+    if token.is_empty() {
+        return Err(format!("token file {} is empty", path.to_string_lossy()));
+    }
+
+    Ok(token.to_string())
+}
+
+/// Read the certificate and private key (both PEM encoded) to serve TLS with
+pub fn read_server_tls(cert: &Path, key: &Path) -> Result<ServerTlsConfig, String> {
+    let identity = Identity::from_pem(read_pem(cert)?, read_pem(key)?);
+
+    Ok(ServerTlsConfig::new().identity(identity))
+}
+
+/// Read the (PEM encoded) certificate of the authority which the certificates of
+/// volumed are verified against
+pub fn read_client_tls(ca: &Path) -> Result<ClientTlsConfig, String> {
+    Ok(ClientTlsConfig::new().ca_certificate(Certificate::from_pem(read_pem(ca)?)))
+}
+
+/// Read a PEM encoded file
+fn read_pem(path: &Path) -> Result<Vec<u8>, String> {
+    std::fs::read(path).map_err(|err| format!("could not read {}: {}", path.to_string_lossy(), err))
+}
+
+/// Build the metadata value carrying a token
+fn bearer(token: &str) -> Result<AsciiMetadataValue, String> {
+    format!("Bearer {}", token)
+        .parse()
+        .map_err(|_| "token must only contain visible ASCII characters".to_string())
+}
+
+/// Interceptor refusing any request which does not carry the shared token
+#[derive(Clone)]
+pub struct RequireToken {
+    expected: AsciiMetadataValue,
+}
+
+impl RequireToken {
+    pub fn new(token: &str) -> Result<Self, String> {
+        Ok(Self {
+            expected: bearer(token)?,
+        })
+    }
+}
+
+impl Interceptor for RequireToken {
+    fn call(&mut self, request: Request<()>) -> Result<Request<()>, Status> {
+        match request.metadata().get(AUTHORIZATION_KEY) {
+            Some(value) if constant_time_eq(value.as_bytes(), self.expected.as_bytes()) => {
+                Ok(request)
+            }
+            _ => Err(Status::unauthenticated("missing or invalid token")),
+        }
+    }
+}
+
+/// Interceptor passing the shared token along with every request, if there is one
+#[derive(Clone, Debug, Default)]
+pub struct WithToken {
+    value: Option<AsciiMetadataValue>,
+}
+
+impl WithToken {
+    pub fn new(token: &str) -> Result<Self, String> {
+        Ok(Self {
+            value: Some(bearer(token)?),
+        })
+    }
+}
+
+impl Interceptor for WithToken {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        if let Some(value) = &self.value {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_KEY, value.clone());
+        }
+
+        Ok(request)
+    }
+}
+
+/// Compare two byte strings in time independent of where they differ, so that the
+/// token cannot be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
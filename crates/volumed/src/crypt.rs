@@ -0,0 +1,91 @@
+//! Helpers for encrypting logical volumes with LUKS.
+
+use std::{
+    ffi::OsStr,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use tonic::Status;
+
+/// Directory holding the device nodes of device mapper devices
+const MAPPER_DIR: &str = "/dev/mapper";
+
+/// Signature reported by `blkid` for LUKS encrypted devices
+pub const LUKS_SIGNATURE: &str = "crypto_LUKS";
+
+/// Set up LUKS encryption on `device`, destroying any data on it
+pub fn format(device: &Path, passphrase: &str) -> Result<(), Status> {
+    cryptsetup(
+        [
+            "luksFormat".as_ref(),
+            "--type".as_ref(),
+            "luks2".as_ref(),
+            "--key-file".as_ref(),
+            "-".as_ref(),
+            device.as_os_str(),
+        ],
+        passphrase,
+    )
+}
+
+/// Open the encrypted `device` as the dm-crypt mapping `name`, returning the path
+/// to the decrypted device.
+pub fn open(device: &Path, name: &str, passphrase: &str) -> Result<PathBuf, Status> {
+    cryptsetup(
+        [
+            "open".as_ref(),
+            "--type".as_ref(),
+            "luks".as_ref(),
+            "--key-file".as_ref(),
+            "-".as_ref(),
+            device.as_os_str(),
+            name.as_ref(),
+        ],
+        passphrase,
+    )?;
+
+    Ok(Path::new(MAPPER_DIR).join(name))
+}
+
+/// Close the dm-crypt mapping `name`
+pub fn close(name: &str) -> Result<(), Status> {
+    cryptsetup(["close".as_ref(), name.as_ref()], "")
+}
+
+/// Run a cryptsetup command, feeding it the passphrase through stdin
+fn cryptsetup<'a, I>(args: I, passphrase: &str) -> Result<(), Status>
+where
+    I: IntoIterator<Item = &'a OsStr>,
+{
+    let mut child = Command::new("cryptsetup")
+        .args(args)
+        .arg("--batch-mode")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| Status::internal(format!("could not run cryptsetup command: {}", err)))?;
+
+    // Dropping stdin closes it, so that cryptsetup does not wait for more input
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(passphrase.as_bytes()).map_err(|err| {
+            Status::internal(format!("could not pass passphrase to cryptsetup: {}", err))
+        })?;
+    }
+
+    let output = child
+        .wait_with_output()
+        .map_err(|err| Status::internal(format!("could not run cryptsetup command: {}", err)))?;
+
+    // Print out the stderr if the command failed
+    if !output.status.success() {
+        return Err(Status::internal(format!(
+            "cryptsetup command failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+
+    Ok(())
+}
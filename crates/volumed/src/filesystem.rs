@@ -0,0 +1,253 @@
+//! Helpers for inspecting and creating filesystems on logical volumes.
+
+use std::{
+    fmt::Display,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::{
+    lvm,
+    spec::{format_options::Reflink, FormatOptions},
+};
+
+/// Exit code used by `blkid` when no signature could be found on the device
+const BLKID_NOT_FOUND: i32 = 2;
+
+/// Exit code used by `e2fsck` when it corrected errors in the filesystem
+const E2FSCK_CORRECTED: i32 = 1;
+
+pub use common::{DEFAULT_FS_TYPE, SUPPORTED_FS_TYPES};
+
+/// Probe a block device for any existing signature (filesystem, RAID member,
+/// partition table, etc.), returning its type if found.
+///
+/// Note: This uses the low-level probing of `blkid`, which bypasses its cache
+/// and reads the device directly.
+pub fn probe(device: &Path) -> Result<Option<String>, Status> {
+    let cmd = Command::new("blkid")
+        .arg("--probe")
+        .args(["--output", "export"])
+        .arg(device)
+        .output()
+        .map_err(|err| Status::internal(format!("could not run blkid command: {}", err)))?;
+
+    if cmd.status.code() == Some(BLKID_NOT_FOUND) {
+        return Ok(None);
+    }
+
+    // Print out the stderr if the command failed
+    if !cmd.status.success() {
+        return Err(Status::internal(format!(
+            "could not probe device `{}`: {}",
+            device.to_string_lossy(),
+            String::from_utf8_lossy(&cmd.stderr)
+        )));
+    }
+
+    // Output is in the form of KEY=value, one per line
+    let output = String::from_utf8_lossy(&cmd.stdout);
+    let signature = output
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .find(|(key, _)| *key == "TYPE" || *key == "PTTYPE")
+        .map(|(_, value)| value.to_string())
+        .unwrap_or_else(|| "unknown".into());
+
+    Ok(Some(signature))
+}
+
+/// Filesystems which can be used to format a logical volume
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    Xfs,
+    Ext4,
+    Btrfs,
+}
+
+impl Filesystem {
+    /// Check that the filesystem can honor the supplied options, so that they are
+    /// rejected instead of being silently ignored.
+    pub fn validate_options(&self, options: &FormatOptions) -> Result<(), Status> {
+        let reflink = options.reflink();
+
+        match self {
+            Self::Xfs => Ok(()),
+            Self::Ext4 if reflink == Reflink::Enabled => {
+                Err(Status::invalid_argument("ext4 does not support reflinks"))
+            }
+            Self::Ext4 => Ok(()),
+            Self::Btrfs if options.inode_size != 0 => Err(Status::invalid_argument(
+                "btrfs does not support setting the inode size",
+            )),
+            Self::Btrfs if reflink == Reflink::Disabled => Err(Status::invalid_argument(
+                "btrfs does not support disabling reflinks",
+            )),
+            Self::Btrfs => Ok(()),
+        }
+    }
+
+    /// Build the mkfs invocation for formatting `device` with the supplied options.
+    ///
+    /// Note: Options that the filesystem cannot honor are rejected (see
+    /// [Self::validate_options]).
+    pub fn mkfs(
+        &self,
+        device: &Path,
+        options: &FormatOptions,
+        force: bool,
+    ) -> Result<Command, Status> {
+        self.validate_options(options)?;
+
+        let reflink = options.reflink();
+        let mut cmd = Command::new(format!("mkfs.{}", self));
+
+        // Never block on an interactive confirmation
+        cmd.stdin(Stdio::null());
+
+        match self {
+            Self::Xfs => {
+                if force {
+                    cmd.arg("-f");
+                }
+                if options.block_size != 0 {
+                    cmd.args(["-b", &format!("size={}", options.block_size)]);
+                }
+                if options.inode_size != 0 {
+                    cmd.args(["-i", &format!("size={}", options.inode_size)]);
+                }
+                match reflink {
+                    Reflink::Default => {}
+                    Reflink::Enabled => {
+                        cmd.args(["-m", "reflink=1"]);
+                    }
+                    Reflink::Disabled => {
+                        cmd.args(["-m", "reflink=0"]);
+                    }
+                }
+            }
+            Self::Ext4 => {
+                if force {
+                    cmd.arg("-F");
+                }
+                if options.block_size != 0 {
+                    cmd.args(["-b", &options.block_size.to_string()]);
+                }
+                if options.inode_size != 0 {
+                    cmd.args(["-I", &options.inode_size.to_string()]);
+                }
+            }
+            Self::Btrfs => {
+                if force {
+                    cmd.arg("-f");
+                }
+                if options.block_size != 0 {
+                    cmd.args(["--sectorsize", &options.block_size.to_string()]);
+                }
+            }
+        }
+
+        cmd.arg(device);
+
+        Ok(cmd)
+    }
+
+    /// Give the filesystem on `device` a new random UUID, so that a copy can be
+    /// mounted alongside the original.
+    ///
+    /// Note: XFS refuses to change the UUID of a filesystem with a dirty log (e.g.
+    /// a copy of a mounted filesystem), so the log is replayed by briefly mounting
+    /// the copy first.
+    pub fn regenerate_uuid(&self, device: &Path) -> Result<(), Status> {
+        let device = device.to_string_lossy().to_string();
+
+        match self {
+            Self::Xfs => {
+                if lvm::run("xfs_admin", ["-U", "generate", &device]).is_ok() {
+                    return Ok(());
+                }
+
+                replay_log(&device)?;
+                lvm::run("xfs_admin", ["-U", "generate", &device])?;
+            }
+            Self::Ext4 => {
+                check_ext4(&device)?;
+                lvm::run("tune2fs", ["-U", "random", &device])?;
+            }
+            Self::Btrfs => {
+                lvm::run("btrfstune", ["-f", "-u", &device])?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Check (and repair) an ext4 filesystem, which `tune2fs` requires before changing
+/// its UUID. Copies of mounted filesystems always need their journal recovered, so
+/// corrected errors are not a failure.
+fn check_ext4(device: &str) -> Result<(), Status> {
+    let cmd = Command::new("e2fsck")
+        .args(["-f", "-p", device])
+        .output()
+        .map_err(|err| Status::internal(format!("could not run e2fsck command: {}", err)))?;
+
+    match cmd.status.code() {
+        Some(0) | Some(E2FSCK_CORRECTED) => Ok(()),
+        _ => Err(Status::internal(format!(
+            "could not check filesystem on `{}`: {}",
+            device,
+            String::from_utf8_lossy(&cmd.stderr).trim()
+        ))),
+    }
+}
+
+/// Replay the log of an XFS filesystem by mounting and unmounting it
+fn replay_log(device: &str) -> Result<(), Status> {
+    let mountpoint = std::env::temp_dir().join(format!("volumed-{}", Uuid::new_v4()));
+    std::fs::create_dir(&mountpoint).map_err(|err| {
+        Status::internal(format!("could not create temporary mountpoint: {}", err))
+    })?;
+
+    // The original may still be mounted, so ignore the duplicate UUID
+    let mountpoint_str = mountpoint.to_string_lossy().to_string();
+    let result = lvm::run(
+        "mount",
+        ["-t", "xfs", "-o", "nouuid", device, &mountpoint_str],
+    )
+    .and_then(|_| lvm::run("umount", [&mountpoint_str]));
+
+    let _ = std::fs::remove_dir(&mountpoint);
+
+    result.map(|_| ())
+}
+
+impl FromStr for Filesystem {
+    type Err = Status;
+
+    fn from_str(fs_type: &str) -> Result<Self, Self::Err> {
+        match fs_type {
+            "xfs" => Ok(Self::Xfs),
+            "ext4" => Ok(Self::Ext4),
+            "btrfs" => Ok(Self::Btrfs),
+            _ => Err(Status::invalid_argument(format!(
+                "unsupported filesystem type `{}`: must be one of {:?}",
+                fs_type, SUPPORTED_FS_TYPES
+            ))),
+        }
+    }
+}
+
+impl Display for Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Xfs => "xfs",
+            Self::Ext4 => "ext4",
+            Self::Btrfs => "btrfs",
+        })
+    }
+}
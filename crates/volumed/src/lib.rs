@@ -1,16 +1,197 @@
 use serde::Deserialize;
 
+pub mod auth;
+pub mod crypt;
+pub mod filesystem;
+pub mod lvm;
+pub mod mounts;
+pub mod reservation;
 pub mod server;
+pub mod thin;
+pub mod trash;
+pub mod wipe;
 
 pub mod spec {
     tonic::include_proto!("volumed");
 }
 
 #[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "ConfigFormat")]
 pub struct Config {
-    /// The [VolumeGroup] to manage
-    pub volume_group: String,
+    /// The [VolumeGroup]s to manage. The first one is used whenever a request
+    /// does not select a specific one.
+    pub volume_groups: Vec<VolumeGroupConfig>,
+}
+
+/// The accepted shapes of the config file, which older releases limited to a
+/// single top-level volume group
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ConfigFormat {
+    Multiple {
+        volume_groups: Vec<VolumeGroupConfig>,
+    },
+    Single {
+        volume_group: String,
+        spare_bytes: Option<usize>,
+        thin_pool: Option<ThinPoolConfig>,
+        #[serde(default)]
+        wipe_policy: WipePolicy,
+        trash: Option<TrashConfig>,
+        #[serde(default)]
+        adopt_legacy_volumes: bool,
+    },
+}
+
+impl TryFrom<ConfigFormat> for Config {
+    type Error = String;
+
+    fn try_from(format: ConfigFormat) -> Result<Self, Self::Error> {
+        let volume_groups = match format {
+            ConfigFormat::Multiple { volume_groups } => volume_groups,
+            ConfigFormat::Single {
+                volume_group,
+                spare_bytes,
+                thin_pool,
+                wipe_policy,
+                trash,
+                adopt_legacy_volumes,
+            } => vec![VolumeGroupConfig {
+                name: volume_group,
+                spare_bytes,
+                thin_pool,
+                wipe_policy,
+                trash,
+                adopt_legacy_volumes,
+            }],
+        };
+
+        if volume_groups.is_empty() {
+            return Err("config must specify at least one volume group".into());
+        }
+
+        Ok(Self { volume_groups })
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct VolumeGroupConfig {
+    /// The name of the [VolumeGroup]
+    pub name: String,
 
     /// The optional amount of bytes to reserve free
+    ///
+    /// Note: Thin volumes are limited by the overcommit ratio of their pool instead,
+    /// so this only limits how far the pool may be auto-extended.
     pub spare_bytes: Option<usize>,
+
+    /// The optional thin pool to allocate volumes from, instead of allocating
+    /// thick volumes directly from the [VolumeGroup]
+    pub thin_pool: Option<ThinPoolConfig>,
+
+    /// How to wipe the data of volumes before removing them
+    #[serde(default)]
+    pub wipe_policy: WipePolicy,
+
+    /// Keep deleted volumes around for a while, so that they can be restored.
+    /// Volumes are removed right away if unset.
+    pub trash: Option<TrashConfig>,
+
+    /// Bring the volumes created by older releases of the CSI controller under
+    /// management on startup. Other volumes have to be adopted explicitly.
+    #[serde(default)]
+    pub adopt_legacy_volumes: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TrashConfig {
+    /// How long (in seconds) to keep deleted volumes before purging them
+    pub retention_seconds: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum WipePolicy {
+    /// Leave the data in place. New volumes are zeroed regardless, so it is never
+    /// exposed through them.
+    #[default]
+    None,
+
+    /// Discard the blocks of the volume, which is quick but only guaranteed to clear
+    /// the data on devices that read discarded blocks as zeroes
+    Discard,
+
+    /// Overwrite the volume with zeroes
+    Zero,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ThinPoolConfig {
+    /// The name of the (existing) thin pool within the [VolumeGroup]
+    pub name: String,
+
+    /// The maximum ratio of the virtual size of all thin volumes to the size of the pool
+    #[serde(default = "ThinPoolConfig::default_max_overcommit_ratio")]
+    pub max_overcommit_ratio: f64,
+
+    /// The usage (in percent) of the pool's data or metadata above which the pool is
+    /// extended. Auto-extension is disabled if unset.
+    pub autoextend_threshold: Option<u8>,
+
+    /// By how much (in percent) to extend the pool when over the threshold
+    #[serde(default = "ThinPoolConfig::default_autoextend_percent")]
+    pub autoextend_percent: u8,
+
+    /// Whether to enable zeroing of newly provisioned blocks on the pool if it is
+    /// disabled, instead of refusing to start
+    #[serde(default)]
+    pub enable_zeroing: bool,
+}
+
+impl ThinPoolConfig {
+    fn default_max_overcommit_ratio() -> f64 {
+        1.0
+    }
+
+    fn default_autoextend_percent() -> u8 {
+        20
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_volume_group_keeps_all_settings() {
+        let config: Config = serde_yaml::from_str(
+            "
+volume_group: volumes
+spare_bytes: 1024
+wipe_policy: zero
+trash:
+  retention_seconds: 60
+",
+        )
+        .unwrap();
+
+        let [vg_config] = config.volume_groups.as_slice() else {
+            panic!("expected a single volume group: {:?}", config.volume_groups);
+        };
+        assert_eq!(vg_config.name, "volumes");
+        assert_eq!(vg_config.spare_bytes, Some(1024));
+        assert_eq!(vg_config.wipe_policy, WipePolicy::Zero);
+        assert_eq!(
+            vg_config
+                .trash
+                .as_ref()
+                .map(|trash| trash.retention_seconds),
+            Some(60)
+        );
+    }
+
+    #[test]
+    fn rejects_empty_volume_groups() {
+        assert!(serde_yaml::from_str::<Config>("volume_groups: []").is_err());
+    }
 }
@@ -0,0 +1,378 @@
+//! Helpers for LVM2 functionality not (yet) exposed by [lvm2_cmd].
+//!
+//! These shell out to the LVM2 command line tools directly and parse their
+//! output into simple structures.
+
+use std::{collections::HashMap, ffi::OsStr, process::Command};
+
+use tonic::Status;
+
+/// Separator used when asking LVM2 for machine-readable reports.
+///
+/// Note: LVM2 does not allow `|` in names or tags, so it is safe to split on.
+const REPORT_SEPARATOR: &str = "|";
+
+/// Represents a snapshot of a logical volume, as reported by `lvs`
+#[derive(Clone, Debug)]
+pub struct SnapshotReport {
+    pub uuid: String,
+    pub name: String,
+    pub volume_group: String,
+    pub origin_uuid: String,
+    pub origin_size: u64,
+    pub creation_time: i64,
+    pub tags: Vec<String>,
+}
+
+/// Details of a logical volume not tracked by [lvm2_cmd], as reported by `lvs`
+#[derive(Clone, Debug, Default)]
+pub struct LvReport {
+    pub tags: Vec<String>,
+
+    /// Whether the volume is activated
+    pub active: bool,
+
+    /// Whether the device of the volume is open (e.g. mounted)
+    pub open: bool,
+
+    /// Whether some of the physical volumes of the volume are missing
+    pub partial: bool,
+
+    /// Health status reported by LVM, empty if healthy
+    pub health: String,
+}
+
+/// Usage of a thin pool, as reported by `lvs`
+#[derive(Clone, Debug)]
+pub struct ThinPoolReport {
+    /// Size of the data of the pool
+    pub data_bytes: u64,
+
+    /// Percentage of the data in use
+    pub data_percent: f64,
+
+    /// Size of the metadata of the pool
+    pub metadata_bytes: u64,
+
+    /// Percentage of the metadata in use
+    pub metadata_percent: f64,
+
+    /// Sum of the virtual sizes of all thin volumes in the pool
+    pub virtual_bytes: u64,
+
+    /// Whether newly provisioned blocks are zeroed before being written to
+    pub zero_new_blocks: bool,
+}
+
+/// Extent usage of a volume group, as reported by `vgs`
+#[derive(Clone, Debug)]
+pub struct VolumeGroupReport {
+    /// Size of a single extent, which all allocations are rounded to
+    pub extent_size: u64,
+
+    /// Size of all extents usable for volumes (i.e. excluding LVM metadata)
+    pub total_bytes: u64,
+
+    /// Size of the extents not allocated to any volume yet
+    pub free_bytes: u64,
+
+    /// Whether volumes have to be allocated from contiguous extents
+    pub contiguous: bool,
+}
+
+/// Run an external command, returning its stdout if it succeeded.
+pub fn run<I, S>(program: &str, args: I) -> Result<String, Status>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let cmd = Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|err| Status::internal(format!("could not run {} command: {}", program, err)))?;
+
+    // Print out the stderr if the command failed
+    if !cmd.status.success() {
+        return Err(Status::internal(format!(
+            "{} command failed: {}",
+            program,
+            String::from_utf8_lossy(&cmd.stderr).trim()
+        )));
+    }
+
+    Ok(String::from_utf8_lossy(&cmd.stdout).to_string())
+}
+
+/// Run an LVM2 reporting command (`lvs`, `vgs`, `pvs`) for the specified fields.
+///
+/// Returns one row per reported object, with the columns in the same order as
+/// the requested fields. Sizes are reported in bytes, and times in seconds since
+/// the UNIX epoch.
+pub fn report(command: &str, fields: &[&str], target: &str) -> Result<Vec<Vec<String>>, Status> {
+    report_with_args(command, fields, &[target])
+}
+
+/// Run an LVM2 reporting command like [report], with arbitrary extra arguments
+/// (e.g. selection criteria) instead of a single target.
+fn report_with_args(
+    command: &str,
+    fields: &[&str],
+    args: &[&str],
+) -> Result<Vec<Vec<String>>, Status> {
+    let fields = fields.join(",");
+    let output = run(
+        command,
+        [
+            "--noheadings",
+            "--nosuffix",
+            "--units",
+            "b",
+            "--separator",
+            REPORT_SEPARATOR,
+            "--config",
+            "report/time_format=\"%s\"",
+            "--options",
+            &fields,
+        ]
+        .iter()
+        .chain(args),
+    )?;
+
+    Ok(output
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            line.split(REPORT_SEPARATOR)
+                .map(|column| column.trim().to_string())
+                .collect()
+        })
+        .collect())
+}
+
+/// List all of the snapshots present in a volume group
+pub fn snapshots(volume_group: &str) -> Result<Vec<SnapshotReport>, Status> {
+    let rows = report(
+        "lvs",
+        &[
+            "lv_uuid",
+            "lv_name",
+            "vg_name",
+            "origin_uuid",
+            "origin_size",
+            "lv_time",
+            "lv_tags",
+        ],
+        volume_group,
+    )?;
+
+    rows.into_iter()
+        // Only snapshots have an origin
+        .filter(|row| row.len() == 7 && !row[3].is_empty())
+        .map(|row| {
+            Ok(SnapshotReport {
+                uuid: row[0].clone(),
+                name: row[1].clone(),
+                volume_group: row[2].clone(),
+                origin_uuid: row[3].clone(),
+                origin_size: parse_number(&row[4])?,
+                creation_time: parse_number(&row[5])?,
+                tags: parse_tags(&row[6]),
+            })
+        })
+        .collect()
+}
+
+/// Get the details of all logical volumes in a volume group, keyed by UUID
+pub fn lv_reports(volume_group: &str) -> Result<HashMap<String, LvReport>, Status> {
+    let rows = report(
+        "lvs",
+        &["lv_uuid", "lv_tags", "lv_attr", "lv_health_status"],
+        volume_group,
+    )?;
+
+    Ok(rows
+        .into_iter()
+        .filter(|row| row.len() == 4)
+        .map(|row| {
+            // See the lv_attr bits in lvs(8): the 5th is the state, the 6th whether the
+            //  device is open and the 9th the volume health
+            let attrs: Vec<char> = row[2].chars().collect();
+            let attr = |index: usize| attrs.get(index).copied().unwrap_or('-');
+
+            let report = LvReport {
+                tags: parse_tags(&row[1]),
+                active: attr(4) == 'a',
+                open: attr(5) == 'o',
+                partial: attr(8) == 'p',
+                health: row[3].clone(),
+            };
+
+            (row[0].clone(), report)
+        })
+        .collect())
+}
+
+/// Get the usage of a thin pool in a volume group
+pub fn thin_pool(volume_group: &str, pool: &str) -> Result<ThinPoolReport, Status> {
+    let rows = report(
+        "lvs",
+        &[
+            "lv_name",
+            "pool_lv",
+            "lv_size",
+            "data_percent",
+            "lv_metadata_size",
+            "metadata_percent",
+            "zero",
+        ],
+        volume_group,
+    )?;
+
+    let pool_row = rows
+        .iter()
+        .find(|row| row.len() == 7 && row[0] == pool)
+        .ok_or_else(|| {
+            Status::not_found(format!(
+                "could not find thin pool `{}` in volume group `{}`",
+                pool, volume_group
+            ))
+        })?;
+
+    // Thin volumes (and their thin snapshots) reference the pool they belong to
+    let virtual_bytes = rows
+        .iter()
+        .filter(|row| row.len() == 7 && row[1] == pool)
+        .map(|row| parse_number::<u64>(&row[2]))
+        .sum::<Result<u64, Status>>()?;
+
+    Ok(ThinPoolReport {
+        data_bytes: parse_number(&pool_row[2])?,
+        data_percent: parse_percent(&pool_row[3])?,
+        metadata_bytes: parse_number(&pool_row[4])?,
+        metadata_percent: parse_percent(&pool_row[5])?,
+        virtual_bytes,
+        zero_new_blocks: pool_row[6] == "zero",
+    })
+}
+
+/// Get the extent usage of a volume group
+pub fn volume_group(volume_group: &str) -> Result<VolumeGroupReport, Status> {
+    let rows = report(
+        "vgs",
+        &[
+            "vg_extent_size",
+            "vg_extent_count",
+            "vg_free_count",
+            "vg_allocation_policy",
+        ],
+        volume_group,
+    )?;
+
+    let row = rows.into_iter().find(|row| row.len() == 4).ok_or_else(|| {
+        Status::not_found(format!("could not find volume group `{}`", volume_group))
+    })?;
+
+    let extent_size: u64 = parse_number(&row[0])?;
+    Ok(VolumeGroupReport {
+        extent_size,
+        total_bytes: extent_size * parse_number::<u64>(&row[1])?,
+        free_bytes: extent_size * parse_number::<u64>(&row[2])?,
+        contiguous: row[3] == "contiguous",
+    })
+}
+
+/// Get the size of the largest contiguous run of free extents in a volume group
+pub fn largest_free_segment(volume_group: &str) -> Result<u64, Status> {
+    let extent_size = self::volume_group(volume_group)?.extent_size;
+    let selection = format!("vg_name={}", volume_group);
+    let rows = report_with_args(
+        "pvs",
+        &["pvseg_size", "lv_name"],
+        &["--segments", "--select", &selection],
+    )?;
+
+    largest_free_extents(&rows).map(|extents| extents * extent_size)
+}
+
+/// Get the number of extents of the largest free segment in a `pvs --segments` report
+/// of the `pvseg_size` and `lv_name` fields
+fn largest_free_extents(rows: &[Vec<String>]) -> Result<u64, Status> {
+    // Free segments do not belong to any volume, and are sized in extents
+    Ok(rows
+        .iter()
+        .filter(|row| row.len() == 2 && row[1].is_empty())
+        .map(|row| parse_number::<u64>(&row[0]))
+        .collect::<Result<Vec<_>, Status>>()?
+        .into_iter()
+        .max()
+        .unwrap_or_default())
+}
+
+/// Parse the (comma separated) tags column of an LVM2 report
+fn parse_tags(column: &str) -> Vec<String> {
+    column
+        .split(',')
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Parse a numeric column of an LVM2 report
+pub(crate) fn parse_number<T>(column: &str) -> Result<T, Status>
+where
+    T: std::str::FromStr,
+    T::Err: ToString,
+{
+    column.parse().map_err(|err: T::Err| {
+        Status::internal(format!(
+            "invalid numeric value `{}` in lvm report: {}",
+            column,
+            err.to_string()
+        ))
+    })
+}
+
+/// Parse a percentage column of an LVM2 report, which is empty for inactive volumes
+fn parse_percent(column: &str) -> Result<f64, Status> {
+    if column.is_empty() {
+        return Ok(0.0);
+    }
+
+    parse_number(column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows(rows: &[(&str, &str)]) -> Vec<Vec<String>> {
+        rows.iter()
+            .map(|(size, lv)| vec![size.to_string(), lv.to_string()])
+            .collect()
+    }
+
+    #[test]
+    fn finds_largest_free_segment() {
+        for (segments, largest) in [
+            (rows(&[]), 0),
+            (rows(&[("100", "data")]), 0),
+            (
+                rows(&[("10", ""), ("100", "data"), ("25", ""), ("5", "")]),
+                25,
+            ),
+            // Malformed rows are ignored
+            (
+                rows(&[("10", "")]).into_iter().chain([vec![]]).collect(),
+                10,
+            ),
+        ] {
+            assert_eq!(largest_free_extents(&segments).unwrap(), largest);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_segment_sizes() {
+        assert!(largest_free_extents(&rows(&[("many", "")])).is_err());
+    }
+}
@@ -1,13 +1,18 @@
-use std::path::PathBuf;
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use clap::Parser;
-use futures_util::FutureExt;
 use lvm2_cmd::{vg::VolumeGroup, InvalidResourceNameError, ResourceName};
-use tokio::net::UnixListener;
+use tokio::{net::UnixListener, sync::watch::Receiver};
 use tokio_stream::wrappers::UnixListenerStream;
 
-use tonic::{transport::Server, Request, Status};
-use volumed::{server::VolumedServer, Config};
+use tonic::{service::interceptor::InterceptedService, transport::Server};
+use volumed::{
+    auth::{self, RequireToken},
+    lvm,
+    server::{self, VolumedServer},
+    spec::volume_service_server::VolumeServiceServer,
+    thin, trash, Config,
+};
 
 #[derive(Parser)]
 struct Cli {
@@ -18,6 +23,27 @@ struct Cli {
     /// Path to the listening socket
     #[clap(short, long, default_value = "/run/volumed/volumed.sock")]
     socket_path: PathBuf,
+
+    /// Also listen on a TCP address, so that a cluster controller can reach this
+    /// node (e.g. `0.0.0.0:50051`).
+    ///
+    /// Note: Only loopback addresses may be used without a `--token-file` and a
+    /// `--tls-cert`.
+    #[clap(short, long)]
+    listen_address: Option<SocketAddr>,
+
+    /// Path to a file holding the token that clients connecting over TCP must
+    /// authenticate with
+    #[clap(short, long)]
+    token_file: Option<PathBuf>,
+
+    /// Path to the (PEM encoded) certificate to secure TCP connections with
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// Path to the (PEM encoded) private key of the `--tls-cert`
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
 }
 
 #[tokio::main]
@@ -47,33 +73,170 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     log::info!("Found config: {:?}", cfg);
 
-    // Ensure that we can see the volume group
-    let resource = cfg
-        .volume_group
-        .clone()
-        .try_into()
-        .map_err(|err: InvalidResourceNameError| err.to_string())?;
-    let vg = VolumeGroup::from_id(&resource).map_err(|err| {
-        format!(
-            "could not find specified volume group `{}`: {}",
-            &resource,
-            err.to_string()
-        )
-    })?;
+    // Anyone who can reach the TCP address could otherwise manage the volumes
+    let token = match (&args.listen_address, &args.token_file) {
+        (_, Some(path)) => Some(RequireToken::new(&auth::read_token(path)?)?),
+        (Some(address), None) if !address.ip().is_loopback() => {
+            return Err(format!(
+                "refusing to listen on non-loopback address `{}` without a `--token-file`",
+                address
+            )
+            .into())
+        }
+        _ => None,
+    };
 
-    log::info!("managing volume group `{}`: {:?}", resource, vg);
+    // ...and the token (as well as the passphrases of encrypted volumes) would be sent
+    //  in plaintext
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(auth::read_server_tls(cert, key)?),
+        _ => None,
+    };
+    let plaintext = args
+        .listen_address
+        .filter(|address| !address.ip().is_loopback() && tls.is_none());
+    if let Some(address) = plaintext {
+        return Err(format!(
+            "refusing to listen on non-loopback address `{}` without `--tls-cert` and `--tls-key`",
+            address
+        )
+        .into());
+    }
 
-    // Ensure that the spare_bytes aren't larger than the capacity
-    if let Some(spare_bytes) = &cfg.spare_bytes {
-        if *vg.capacity_bytes <= *spare_bytes {
-            return Err(format!(
-                "capacity of managed volume ({}) is not larger than the requested spare_bytes ({})",
-                vg.capacity_bytes, spare_bytes
+    for vg_cfg in &cfg.volume_groups {
+        // Ensure that we can see the volume group
+        let resource: ResourceName = vg_cfg
+            .name
+            .clone()
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| err.to_string())?;
+        let vg = VolumeGroup::from_id(&resource).map_err(|err| {
+            format!(
+                "could not find specified volume group `{}`: {}",
+                &resource, err
             )
-            .into());
+        })?;
+
+        log::info!("managing volume group `{}`: {:?}", resource, vg);
+
+        // Volumes created before volumed tracked the ones it manages must not be lost
+        if vg_cfg.adopt_legacy_volumes {
+            server::adopt_legacy_volumes(&vg).map_err(|status| status.message().to_string())?;
+        }
+
+        // Ensure that the spare_bytes aren't larger than the capacity
+        if let Some(spare_bytes) = &vg_cfg.spare_bytes {
+            if *vg.capacity_bytes <= *spare_bytes {
+                return Err(format!(
+                    "capacity of managed volume group `{}` ({}) is not larger than the requested spare_bytes ({})",
+                    resource, vg.capacity_bytes, spare_bytes
+                )
+                .into());
+            }
+        }
+
+        // Ensure that we can see the thin pool, if any
+        if let Some(thin_pool) = &vg_cfg.thin_pool {
+            if thin_pool.max_overcommit_ratio < 1.0 {
+                return Err(format!(
+                    "max_overcommit_ratio of thin pool `{}` must be at least 1.0: got {}",
+                    thin_pool.name, thin_pool.max_overcommit_ratio
+                )
+                .into());
+            }
+
+            if let Some(threshold) = thin_pool.autoextend_threshold {
+                if !(1..=100).contains(&threshold) {
+                    return Err(format!(
+                        "autoextend_threshold of thin pool `{}` must be between 1 and 100: got {}",
+                        thin_pool.name, threshold
+                    )
+                    .into());
+                }
+            }
+            if thin_pool.autoextend_percent == 0 {
+                return Err(format!(
+                    "autoextend_percent of thin pool `{}` must be larger than 0",
+                    thin_pool.name
+                )
+                .into());
+            }
+
+            let report = lvm::thin_pool(&vg_cfg.name, &thin_pool.name)
+                .map_err(|status| status.message().to_string())?;
+
+            log::info!(
+                "allocating volumes of `{}` from thin pool `{}`: {:?}",
+                resource,
+                thin_pool.name,
+                report
+            );
+
+            // Blocks provisioned for new thin volumes must not expose stale data, but only
+            //  change how the pool behaves if asked to
+            if !report.zero_new_blocks {
+                if !thin_pool.enable_zeroing {
+                    return Err(format!(
+                        "thin pool `{}` does not zero new blocks, which would expose stale data to new volumes: run `lvchange --zero y {}/{}` or set `enable_zeroing`",
+                        thin_pool.name, vg_cfg.name, thin_pool.name
+                    )
+                    .into());
+                }
+
+                log::warn!(
+                    "enabling zeroing of new blocks in thin pool `{}`",
+                    thin_pool.name
+                );
+
+                lvm::run(
+                    "lvchange",
+                    [
+                        "--zero".to_string(),
+                        "y".into(),
+                        format!("{}/{}", vg_cfg.name, thin_pool.name),
+                    ],
+                )
+                .map_err(|status| status.message().to_string())?;
+            }
+
+            if let Some(threshold) = thin_pool.autoextend_threshold {
+                tokio::spawn(thin::autoextend(
+                    vg_cfg.name.clone(),
+                    thin_pool.clone(),
+                    threshold,
+                    vg_cfg.spare_bytes.unwrap_or_default() as u64,
+                ));
+            }
         }
     }
 
+    // Purge deleted volumes once their retention period is over
+    for vg_cfg in &cfg.volume_groups {
+        if let Some(trash) = &vg_cfg.trash {
+            log::info!(
+                "keeping deleted volumes of `{}` for {} seconds",
+                vg_cfg.name,
+                trash.retention_seconds
+            );
+
+            tokio::spawn(trash::purge(
+                vg_cfg.clone(),
+                Duration::from_secs(trash.retention_seconds),
+            ));
+        }
+    }
+
+    let controller = Arc::new(VolumedServer::new(cfg));
+
+    // Handle SIGINT cleanly by cleaning up the socket when killed
+    let (tx, rx) = tokio::sync::watch::channel(());
+    ctrlc::set_handler(move || tx.send(()).expect("could not send sigint"))
+        .expect("could not set Ctrl-C handler");
+
+    let shutdown = |mut rx: Receiver<()>| async move {
+        let _ = rx.changed().await;
+    };
+
     // Create the unix socket for communication
     let sock = UnixListener::bind(&args.socket_path)?;
     let sock_stream = UnixListenerStream::new(sock);
@@ -84,21 +247,43 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         args.socket_path.to_string_lossy()
     );
 
-    let controller = VolumedServer::new(cfg.clone());
+    let unix = Server::builder()
+        .add_service(VolumeServiceServer::from_arc(controller.clone()))
+        // Serve until we get a Ctrl^C (or are killed)
+        .serve_with_incoming_shutdown(sock_stream, shutdown(rx.clone()));
 
-    // Handle SIGINT cleanly by cleaning up the socket when killed
-    let (tx, mut rx) = tokio::sync::mpsc::channel(1);
-    ctrlc::set_handler(move || tx.blocking_send(()).expect("could not send sigint"))
-        .expect("could not set Ctrl-C handler");
+    // Serve over TCP for cluster controllers as well, if requested
+    let tcp = async {
+        let address = match args.listen_address {
+            Some(address) => address,
+            None => return Ok(()),
+        };
 
-    // Start listening
-    Server::builder()
-        // Intercept all requests and log them
-        .layer(tonic::service::interceptor(vg_injector(cfg, resource)))
-        .add_service(controller.into_service())
-        // Serve until we get a Ctrl^C (or are killed)
-        .serve_with_incoming_shutdown(sock_stream, rx.recv().map(|_| ()))
-        .await?;
+        log::info!("Starting the volumed service at `{}`", address);
+
+        let mut server = Server::builder();
+        if let Some(tls) = tls {
+            server = server.tls_config(tls)?;
+        }
+
+        let service = VolumeServiceServer::from_arc(controller.clone());
+        match token {
+            Some(token) => {
+                server
+                    .add_service(InterceptedService::new(service, token))
+                    .serve_with_shutdown(address, shutdown(rx.clone()))
+                    .await
+            }
+            None => {
+                server
+                    .add_service(service)
+                    .serve_with_shutdown(address, shutdown(rx.clone()))
+                    .await
+            }
+        }
+    };
+
+    tokio::try_join!(unix, tcp)?;
 
     // Clean up the socket file
     log::info!("Cleaning up socket file...");
@@ -106,21 +291,3 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
-
-/// Intercept a request and append the [VolumeGroup] info to it.
-// TODO: This should probably be consumed by the library...
-fn vg_injector(
-    config: Config,
-    resource: ResourceName,
-) -> impl Fn(Request<()>) -> Result<Request<()>, Status> + Send + Clone {
-    move |mut req| {
-        let info = VolumeGroup::from_id(&resource).map_err(|_| {
-            Status::internal(format!("volume group not found: {}", &config.volume_group))
-        })?;
-
-        // Inject the volume group into the request
-        req.extensions_mut().insert(info);
-
-        Ok(req)
-    }
-}
@@ -0,0 +1,74 @@
+//! Helpers for finding out where devices are mounted.
+
+use std::{
+    collections::HashSet,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use common::unescape;
+use tonic::Status;
+
+/// Location of the mount table for the current mount namespace
+const MOUNT_TABLE: &str = "/proc/self/mounts";
+
+/// Location of the block devices in sysfs
+const SYS_BLOCK_DIR: &str = "/sys/class/block";
+
+/// Get the mountpoints of a device, including those of any devices stacked on top of
+/// it (e.g. dm-crypt mappings).
+///
+/// Note: A device which does not exist (e.g. an inactive volume) is never mounted.
+pub fn mountpoints(device: &Path) -> Result<Vec<PathBuf>, Status> {
+    let device = match fs::canonicalize(device) {
+        Ok(device) => device,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => {
+            return Err(Status::internal(format!(
+                "could not resolve device `{}`: {}",
+                device.to_string_lossy(),
+                err
+            )))
+        }
+    };
+
+    let devices = with_holders(device);
+    let table = fs::read_to_string(MOUNT_TABLE)
+        .map_err(|err| Status::internal(format!("could not read mount table: {}", err)))?;
+
+    Ok(table
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let source = fs::canonicalize(unescape(columns.next()?)).ok()?;
+            let target = unescape(columns.next()?);
+
+            devices.contains(&source).then_some(target)
+        })
+        .collect())
+}
+
+/// Collect a device along with all devices (transitively) holding it
+fn with_holders(device: PathBuf) -> HashSet<PathBuf> {
+    let mut devices = HashSet::new();
+    let mut pending = vec![device];
+
+    while let Some(device) = pending.pop() {
+        if let Some(name) = device.file_name() {
+            let holders = Path::new(SYS_BLOCK_DIR).join(name).join("holders");
+            if let Ok(entries) = fs::read_dir(holders) {
+                pending.extend(
+                    entries
+                        .flatten()
+                        .map(|entry| Path::new("/dev").join(entry.file_name()))
+                        .filter(|holder| !devices.contains(holder)),
+                );
+            }
+        }
+
+        devices.insert(device);
+    }
+
+    devices
+}
@@ -0,0 +1,176 @@
+//! Bookkeeping of the capacity claimed by in-flight allocations.
+//!
+//! Checking for free space and allocating a volume are separate LVM commands, so
+//! concurrent requests could otherwise both pass the check and over-allocate the
+//! volume group. Allocations from a volume group are therefore serialized through
+//! its lock, and the capacity they claim stays reserved until LVM accounts for it.
+//!
+//! Filling in a new volume happens outside of that lock, so the volumes being
+//! created are tracked separately to keep retries from interfering with them.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+
+#[derive(Debug, Default)]
+pub struct Reservations {
+    /// Locks serializing the allocations of each volume group
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<()>>>>,
+
+    /// Bytes claimed by in-flight allocations, per volume group
+    reserved: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Reservations {
+    /// Wait for exclusive access to allocate from a volume group
+    pub async fn lock(&self, volume_group: &str) -> OwnedMutexGuard<()> {
+        let lock = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(volume_group.to_string())
+            .or_default()
+            .clone();
+
+        lock.lock_owned().await
+    }
+
+    /// The bytes currently reserved in a volume group
+    pub fn reserved(&self, volume_group: &str) -> u64 {
+        self.reserved
+            .lock()
+            .unwrap()
+            .get(volume_group)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Reserve bytes in a volume group until the returned [Reservation] is dropped.
+    ///
+    /// Note: This does not check for free space, which is up to the caller while
+    /// holding the lock of the volume group.
+    pub fn reserve(&self, volume_group: &str, bytes: u64) -> Reservation {
+        *self
+            .reserved
+            .lock()
+            .unwrap()
+            .entry(volume_group.to_string())
+            .or_default() += bytes;
+
+        Reservation {
+            reserved: self.reserved.clone(),
+            volume_group: volume_group.to_string(),
+            bytes,
+        }
+    }
+}
+
+/// Capacity reserved in a volume group, released when dropped
+#[derive(Debug)]
+pub struct Reservation {
+    reserved: Arc<Mutex<HashMap<String, u64>>>,
+    volume_group: String,
+    bytes: u64,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some(bytes) = reserved.get_mut(&self.volume_group) {
+            *bytes = bytes.saturating_sub(self.bytes);
+            if *bytes == 0 {
+                reserved.remove(&self.volume_group);
+            }
+        }
+    }
+}
+
+/// The volumes currently being created, by full name (`vg/lv`)
+#[derive(Debug, Default)]
+pub struct InFlight {
+    names: Arc<Mutex<HashSet<String>>>,
+}
+
+impl InFlight {
+    /// Mark a volume as being created until the returned [Creation] is dropped, or
+    /// return [None] if it already is.
+    pub fn start(&self, name: &str) -> Option<Creation> {
+        if !self.names.lock().unwrap().insert(name.to_string()) {
+            return None;
+        }
+
+        Some(Creation {
+            names: self.names.clone(),
+            name: name.to_string(),
+        })
+    }
+}
+
+/// A volume being created, which is no longer in flight when dropped
+#[derive(Debug)]
+pub struct Creation {
+    names: Arc<Mutex<HashSet<String>>>,
+    name: String,
+}
+
+impl Drop for Creation {
+    fn drop(&mut self) {
+        self.names.lock().unwrap().remove(&self.name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reservations_add_up_per_volume_group() {
+        let reservations = Reservations::default();
+
+        let _a = reservations.reserve("fast", 100);
+        let _b = reservations.reserve("fast", 50);
+        let _c = reservations.reserve("slow", 10);
+
+        assert_eq!(reservations.reserved("fast"), 150);
+        assert_eq!(reservations.reserved("slow"), 10);
+        assert_eq!(reservations.reserved("other"), 0);
+    }
+
+    #[test]
+    fn dropping_releases_only_its_own_bytes() {
+        let reservations = Reservations::default();
+
+        let a = reservations.reserve("fast", 100);
+        let b = reservations.reserve("fast", 50);
+
+        drop(a);
+        assert_eq!(reservations.reserved("fast"), 50);
+
+        drop(b);
+        assert_eq!(reservations.reserved("fast"), 0);
+        assert!(reservations.reserved.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn empty_reservations_are_released() {
+        let reservations = Reservations::default();
+
+        drop(reservations.reserve("fast", 0));
+        assert_eq!(reservations.reserved("fast"), 0);
+    }
+
+    #[test]
+    fn creations_are_exclusive_until_dropped() {
+        let creating = InFlight::default();
+
+        let creation = creating.start("volumes/a").unwrap();
+        assert!(creating.start("volumes/a").is_none());
+        assert!(creating.start("volumes/b").is_some());
+
+        drop(creation);
+        assert!(creating.start("volumes/a").is_some());
+    }
+}
@@ -1,75 +1,411 @@
-use std::num::TryFromIntError;
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
+use common::round_to_extents;
 use lvm2_cmd::{
     error::LVMError,
     lv::{LVCreateOptions, LogicalVolume},
     vg::VolumeGroup,
-    InvalidResourceCapacityError, InvalidResourceNameError, InvalidResourceUUIDError,
+    InvalidResourceCapacityError, InvalidResourceNameError, InvalidResourceUUIDError, ResourceName,
     ResourceSelector,
 };
-use tonic::{Request, Response, Status};
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
 
 use crate::{
+    crypt,
+    filesystem::{self, Filesystem},
+    lvm::{self, LvReport, SnapshotReport},
+    mounts,
+    reservation::{InFlight, Reservation, Reservations},
     spec::{
         get_lv_request::Identifier,
+        get_snapshot_request::Identifier as SnapshotIdentifier,
         volume_service_server::{VolumeService, VolumeServiceServer},
-        CreateLvRequest, DeleteLvRequest, Empty, FormatLvRequest, GetFreeBytesResponse,
-        GetLvListResponse, GetLvRequest, LogicalVolume as LV,
+        AdoptLvRequest, CreateLvRequest, CreateSnapshotRequest, DeleteLvRequest,
+        DeleteSnapshotRequest, Empty, EncryptLvRequest, FormatLvRequest, FormatOptions,
+        GetFreeBytesRequest, GetFreeBytesResponse, GetLvListRequest, GetLvListResponse,
+        GetLvRequest, GetSnapshotListRequest, GetSnapshotListResponse, GetSnapshotRequest,
+        LogicalVolume as LV, ResizeLvRequest, RestoreLvRequest, Snapshot, ThinPoolUsage,
     },
-    Config,
+    thin, trash, wipe, Config, VolumeGroupConfig,
 };
 
+/// Tag used to record the filesystem that a [LogicalVolume] was formatted with
+pub const FS_TYPE_TAG: &str = "fs_type";
+
+/// Tag used to mark a [LogicalVolume] as encrypted with LUKS
+pub const ENCRYPTED_TAG: &str = "encrypted";
+
+/// Key of the metadata holding the signature found on a volume when refusing to
+/// format it
+pub const SIGNATURE_METADATA_KEY: &str = "signature";
+
+/// Tag marking the [LogicalVolume]s (and snapshots) which volumed may manage
+pub const MANAGED_TAG: &str = "managed-by=volumed";
+
+/// Prefix of the tag which older releases of the CSI controller put on the volumes they
+/// created, before volumed kept track of the volumes it manages
+const LEGACY_NAME_TAG_PREFIX: &str = "name=";
+
+/// Maximum length of the names which older releases of the CSI controller gave their
+/// volumes, i.e. a 64-bit hash in upper case hexadecimal
+const LEGACY_NAME_MAX_LEN: usize = 16;
+
+/// Tag marking a [LogicalVolume] whose contents are still being filled in (i.e. zeroed
+/// or copied from its source). Such volumes are hidden, and replaced if the creation is
+/// retried.
+const PENDING_TAG: &str = "pending";
+
+/// Prefix of the tag holding the offset (in bytes) from which the extents added to a
+/// thick [LogicalVolume] by a resize still need to be zeroed
+const ZERO_FROM_TAG_PREFIX: &str = "zero-from=";
+
+/// Suffix of the temporary snapshot which the source of a copy is read from, named
+/// after the copy
+const SOURCE_SNAPSHOT_SUFFIX: &str = "-source";
+
 pub struct VolumedServer {
     config: Config,
+    reservations: Reservations,
+    creating: InFlight,
 }
 
 impl VolumedServer {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        Self {
+            config,
+            reservations: Reservations::default(),
+            creating: InFlight::default(),
+        }
     }
 
     pub fn into_service(self) -> VolumeServiceServer<Self> {
         VolumeServiceServer::new(self)
     }
+
+    /// Get the selected managed [VolumeGroup] along with its config, defaulting
+    /// to the first one if none is selected.
+    fn volume_group(&self, selector: &str) -> Result<(VolumeGroup, &VolumeGroupConfig), Status> {
+        let config = if selector.is_empty() {
+            self.config.volume_groups.first()
+        } else {
+            self.config
+                .volume_groups
+                .iter()
+                .find(|config| config.name == selector)
+        }
+        .ok_or_else(|| {
+            Status::invalid_argument(format!(
+                "volume group `{}` is not managed by volumed",
+                selector
+            ))
+        })?;
+
+        Ok((load_volume_group(&config.name)?, config))
+    }
+
+    /// Get all of the managed [VolumeGroup]s, or only the selected one
+    fn volume_groups(
+        &self,
+        selector: &str,
+    ) -> Result<Vec<(VolumeGroup, &VolumeGroupConfig)>, Status> {
+        if !selector.is_empty() {
+            return Ok(vec![self.volume_group(selector)?]);
+        }
+
+        self.config
+            .volume_groups
+            .iter()
+            .map(|config| Ok((load_volume_group(&config.name)?, config)))
+            .collect()
+    }
+
+    /// Find the [LogicalVolume] with the specified name in the managed [VolumeGroup]s,
+    /// or only in the selected one.
+    ///
+    /// Note: Volumes not created (or adopted) by volumed are refused.
+    fn find_lv(
+        &self,
+        selector: &str,
+        name: &ResourceName,
+    ) -> Result<(LogicalVolume, VolumeGroup, &VolumeGroupConfig), Status> {
+        let (lv, vg, config) = self.find_any_lv(selector, name)?;
+        ensure_managed(&lv)?;
+
+        Ok((lv, vg, config))
+    }
+
+    /// Find the [LogicalVolume] with the specified name in the managed [VolumeGroup]s,
+    /// or only in the selected one, regardless of whether volumed manages it.
+    fn find_any_lv(
+        &self,
+        selector: &str,
+        name: &ResourceName,
+    ) -> Result<(LogicalVolume, VolumeGroup, &VolumeGroupConfig), Status> {
+        for (vg, config) in self.volume_groups(selector)? {
+            match LogicalVolume::from_id(&vg.name, name) {
+                Ok(lv) => return Ok((lv, vg, config)),
+                Err(LVMError::NotFound { .. }) => continue,
+                Err(err) => return Err(map_lvm_error(err)),
+            }
+        }
+
+        Err(Status::not_found(format!(
+            "could not find logical volume `{}` in any managed volume group",
+            name
+        )))
+    }
+
+    /// Get the bytes still available for allocations from a [VolumeGroup], accounting
+    /// for its spare bytes and in-flight reservations.
+    ///
+    /// Returns the bytes which are free right away, along with the bytes which are
+    /// only held by deleted volumes in the trash (which can be purged early).
+    fn free_bytes(
+        &self,
+        vg: &VolumeGroup,
+        config: &VolumeGroupConfig,
+    ) -> Result<(u64, u64), Status> {
+        let free = match &config.thin_pool {
+            // Thin volumes are only limited by the overcommit ratio of their pool
+            Some(thin_pool) => {
+                let report = lvm::thin_pool(&vg.name.to_string(), &thin_pool.name)?;
+                thin::free_virtual_bytes(thin_pool, &report)
+            }
+            None => lvm::volume_group(&vg.name.to_string())?
+                .free_bytes
+                .saturating_sub(config.spare_bytes.unwrap_or_default() as u64),
+        };
+
+        let trashed = match &config.trash {
+            Some(_) => trash::purgeable(&config.name)?
+                .iter()
+                .map(|volume| volume.size)
+                .sum(),
+            None => 0,
+        };
+
+        Ok((
+            free.saturating_sub(self.reservations.reserved(&config.name)),
+            trashed,
+        ))
+    }
+
+    /// Reserve bytes for an allocation from a [VolumeGroup], failing if they do not fit.
+    /// Deleted volumes are purged early if their space is needed.
+    ///
+    /// Note: This must be called while holding the allocation lock of the volume group,
+    /// and the reservation held until the allocation is visible to LVM.
+    async fn reserve(
+        &self,
+        vg: &VolumeGroup,
+        config: &VolumeGroupConfig,
+        bytes: u64,
+    ) -> Result<Reservation, Status> {
+        let (free, trashed) = self.free_bytes(vg, config)?;
+        let exhausted = |available: u64| {
+            Status::resource_exhausted(match &config.thin_pool {
+                Some(thin_pool) => format!(
+                    "thin pool `{}` cannot fit {} more bytes without exceeding its overcommit ratio of {}: {} bytes left",
+                    thin_pool.name, bytes, thin_pool.max_overcommit_ratio, available
+                ),
+                None => format!(
+                    "volume group `{}` cannot fit {} more bytes: {} bytes left",
+                    config.name, bytes, available
+                ),
+            })
+        };
+
+        if bytes > free + trashed {
+            return Err(exhausted(free + trashed));
+        }
+
+        if bytes > free {
+            // Purging wipes the volumes, which may take a while
+            let trash_config = config.clone();
+            let freed = blocking(move || trash::reclaim(&trash_config, bytes - free)).await?;
+            if bytes > free + freed {
+                return Err(exhausted(free + freed));
+            }
+        }
+
+        Ok(self.reservations.reserve(&config.name, bytes))
+    }
+
+    /// Take a temporary snapshot of the source of a copy into `target`, so that the copy
+    /// is consistent even if the source is written to in the meantime.
+    ///
+    /// Returns [None] if the source is a snapshot already, which nothing writes to.
+    async fn snapshot_source(
+        &self,
+        source: &LogicalVolume,
+        vg: &VolumeGroup,
+        config: &VolumeGroupConfig,
+        target: &ResourceName,
+    ) -> Result<Option<SourceSnapshot>, Status> {
+        let uuid = source.uuid.to_string();
+        if lvm::snapshots(&vg.name.to_string())?
+            .iter()
+            .any(|snapshot| snapshot.uuid == uuid)
+        {
+            return Ok(None);
+        }
+
+        let name = format!("{}{}", target, SOURCE_SNAPSHOT_SUFFIX);
+        let resource: ResourceName = name
+            .clone()
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        let _allocation = self.reservations.lock(&config.name).await;
+
+        // Only an interrupted attempt at the same copy can have left a snapshot behind,
+        //  as no other attempt is in flight
+        match LogicalVolume::from_id(&vg.name, &resource) {
+            Ok(_) => {
+                log::warn!(
+                    "removing snapshot `{}/{}` left by an earlier attempt",
+                    vg.name,
+                    name
+                );
+
+                vg.remove_lv(&resource).map_err(map_lvm_error)?;
+            }
+            Err(LVMError::NotFound { .. }) => {}
+            Err(err) => return Err(map_lvm_error(err)),
+        }
+
+        let _reservation = self.reserve(vg, config, lv_size(source)).await?;
+
+        let mut args = snapshot_args(&name, config);
+        args.push(format!("{}/{}", vg.name, source.name));
+        lvm::run("lvcreate", args)?;
+
+        Ok(Some(SourceSnapshot {
+            full_name: format!("{}/{}", vg.name, name),
+            path: Path::new("/dev").join(vg.name.to_string()).join(&name),
+        }))
+    }
+
+    /// Find the first snapshot in the managed [VolumeGroup]s (or only in the
+    /// selected one) matching the predicate.
+    fn find_snapshot<P>(&self, selector: &str, predicate: P) -> Result<SnapshotReport, Status>
+    where
+        P: Fn(&SnapshotReport) -> bool,
+    {
+        for (vg, _) in self.volume_groups(selector)? {
+            if let Some(snapshot) = lvm::snapshots(&vg.name.to_string())?
+                .into_iter()
+                .filter(is_managed_snapshot)
+                .find(&predicate)
+            {
+                return Ok(snapshot);
+            }
+        }
+
+        Err(Status::not_found(
+            "could not find matching snapshot in any managed volume group",
+        ))
+    }
 }
 
 #[tonic::async_trait]
 impl VolumeService for VolumedServer {
     async fn get_lv_list(
         &self,
-        request: Request<Empty>,
+        request: Request<GetLvListRequest>,
     ) -> Result<Response<GetLvListResponse>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap();
+        let req = request.into_inner();
 
-        let reply = vg
-            .list_lvs()
-            .map_err(map_lvm_error)?
-            .into_iter()
-            .map(LogicalVolume::into)
-            .collect();
+        let mut volumes: Vec<LV> = vec![];
+        for (vg, _) in self.volume_groups(&req.volume_group)? {
+            // Snapshots are not volumes in their own right, so hide them from the listing
+            let snapshots: Vec<_> = lvm::snapshots(&vg.name.to_string())?
+                .into_iter()
+                .map(|snapshot| snapshot.uuid)
+                .collect();
+
+            let mut reports = lvm::lv_reports(&vg.name.to_string())?;
+            volumes.extend(
+                vg.list_lvs()
+                    .map_err(map_lvm_error)?
+                    .into_iter()
+                    .filter(|lv| !snapshots.contains(&lv.uuid.to_string()))
+                    .map(|lv| {
+                        let lv: LV = lv.into();
+                        let report = reports.remove(&lv.uuid).unwrap_or_default();
+
+                        with_report(lv, report)
+                    })
+                    .filter(|lv| lv.tags.iter().any(|tag| tag == MANAGED_TAG))
+                    .filter(|lv| !lv.tags.iter().any(|tag| tag == PENDING_TAG))
+                    .filter(|lv| !trash::is_trashed(&lv.tags)),
+            );
+        }
 
-        Ok(Response::new(GetLvListResponse { volumes: reply }))
+        let (volumes, next_token) = paginate(volumes, &req.starting_token, req.max_entries)?;
+
+        Ok(Response::new(GetLvListResponse {
+            volumes,
+            next_token,
+        }))
     }
 
     async fn get_free_bytes(
         &self,
-        request: Request<Empty>,
+        request: Request<GetFreeBytesRequest>,
     ) -> Result<Response<GetFreeBytesResponse>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap();
-        let spare_bytes = self.config.spare_bytes.unwrap_or_default();
+        let (vg, config) = self.volume_group(&request.get_ref().volume_group)?;
+        let (free, trashed) = self.free_bytes(&vg, config)?;
+        let report = lvm::volume_group(&vg.name.to_string())?;
+
+        // Report the usage of the thin pool as well, if any
+        let (total, thin_pool) = match &config.thin_pool {
+            Some(thin_pool) => {
+                let report = lvm::thin_pool(&vg.name.to_string(), &thin_pool.name)?;
+                let used = |bytes: u64, percent: f64| (bytes as f64 * percent / 100.0) as u64;
+
+                let usage = ThinPoolUsage {
+                    name: thin_pool.name.clone(),
+                    data_bytes: report.data_bytes,
+                    data_used_bytes: used(report.data_bytes, report.data_percent),
+                    metadata_bytes: report.metadata_bytes,
+                    metadata_used_bytes: used(report.metadata_bytes, report.metadata_percent),
+                };
+
+                (thin::max_virtual_bytes(thin_pool, &report), Some(usage))
+            }
+            None => (
+                report
+                    .total_bytes
+                    .saturating_sub(config.spare_bytes.unwrap_or_default() as u64),
+                None,
+            ),
+        };
+
+        // Deleted volumes are purged early whenever their space is needed
+        let bytes_free = free + trashed;
+
+        // Volumes are normally free to span multiple runs of extents (or even physical
+        //  volumes), unless the allocation policy asks otherwise
+        let max_volume_bytes = if config.thin_pool.is_none() && report.contiguous {
+            lvm::largest_free_segment(&vg.name.to_string())?.min(bytes_free)
+        } else {
+            bytes_free
+        };
 
         Ok(Response::new(GetFreeBytesResponse {
-            bytes_free: vg
-                .capacity_bytes
-                .checked_sub(spare_bytes)
-                .unwrap_or(0)
-                .try_into()
-                .map_err(|err: TryFromIntError| {
-                    Status::internal(format!(
-                        "could not cast capacity into a u64: {}",
-                        err.to_string()
-                    ))
-                })?,
+            bytes_free,
+            thin_pool,
+            bytes_total: total,
+            extent_size: report.extent_size,
+            // Spare bytes are not necessarily a whole number of extents
+            max_volume_bytes: match report.extent_size {
+                0 => max_volume_bytes,
+                extent_size => max_volume_bytes - max_volume_bytes % extent_size,
+            },
         }))
     }
 
@@ -77,38 +413,248 @@ impl VolumeService for VolumedServer {
         &self,
         request: Request<CreateLvRequest>,
     ) -> Result<Response<LV>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap().clone();
         let req = request.into_inner();
+        let (vg, config) = self.volume_group(&req.volume_group)?;
+
+        // LVM allocates whole extents, so account for the size it actually allocates
+        let extent_size = lvm::volume_group(&vg.name.to_string())?.extent_size;
+        let size = round_to_extents(req.capacity, extent_size);
 
-        let capacity = req
-            .capacity
+        let capacity = size
             .try_into()
             .map_err(|err: InvalidResourceCapacityError| {
                 Status::invalid_argument(err.to_string())
             })?;
 
-        let name = req
+        let name: ResourceName = req
             .name
             .try_into()
             .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
 
-        let lv = vg
-            .add_lv(LVCreateOptions {
+        // Make sure that the source fits before allocating anything
+        let source = if req.source_name.is_empty() {
+            None
+        } else {
+            let source_name: ResourceName =
+                req.source_name
+                    .try_into()
+                    .map_err(|err: InvalidResourceNameError| {
+                        Status::invalid_argument(err.to_string())
+                    })?;
+            let (source, source_vg, source_config) =
+                self.find_lv(&req.source_volume_group, &source_name)?;
+
+            if lv_size(&source) > size {
+                return Err(Status::out_of_range(format!(
+                    "cannot copy source `{}` of {} bytes into a volume of {} bytes",
+                    source.name,
+                    lv_size(&source),
+                    size
+                )));
+            }
+
+            Some((source, source_vg, source_config))
+        };
+
+        // A retry must neither remove the volume while it is still being filled in by an
+        //  earlier attempt, nor have its own volume removed when that attempt fails
+        let full_name = format!("{}/{}", vg.name, name);
+        let creation = self.creating.start(&full_name).ok_or_else(|| {
+            Status::aborted(format!(
+                "logical volume `{}` is already being created",
+                full_name
+            ))
+        })?;
+        let creation = Arc::new(creation);
+
+        // Copies are read from a snapshot of their source, which is removed once dropped
+        let source = match source {
+            Some((source, source_vg, source_config)) => {
+                let snapshot = self
+                    .snapshot_source(&source, &source_vg, source_config, &name)
+                    .await?;
+
+                Some((source.path, snapshot))
+            }
+            None => None,
+        };
+
+        // Claim the capacity before allocating, so that concurrent requests cannot
+        //  both fit into the same free space
+        let mut tags = req.tags;
+        tags.push(MANAGED_TAG.into());
+
+        let allocation = self.reservations.lock(&config.name).await;
+
+        // Clean up after an earlier attempt which was interrupted while filling in the volume.
+        //  No other attempt is in flight, so it cannot still be filling in the volume.
+        match LogicalVolume::from_id(&vg.name, &name) {
+            Ok(existing) if lv_tags(&existing)?.iter().any(|tag| tag == PENDING_TAG) => {
+                log::warn!(
+                    "removing incomplete logical volume `{}/{}` left by an earlier attempt",
+                    vg.name,
+                    name
+                );
+
+                vg.remove_lv(&existing.name).map_err(map_lvm_error)?;
+            }
+            Ok(_) | Err(LVMError::NotFound { .. }) => {}
+            Err(err) => return Err(map_lvm_error(err)),
+        }
+
+        // Keep the volume hidden until its contents are in place. Only fresh thin volumes
+        //  are ready right away, as their unprovisioned blocks already read as zeroes.
+        let pending = source.is_some() || config.thin_pool.is_none();
+        if pending {
+            tags.push(PENDING_TAG.into());
+        }
+
+        let reservation = self.reserve(&vg, config, size).await?;
+
+        // Thin volumes are not supported by lvm2_cmd, so create them manually
+        let lv = if let Some(thin_pool) = &config.thin_pool {
+            let mut args = vec![
+                "--thin".to_string(),
+                "--virtualsize".into(),
+                format!("{}b", size),
+                "--name".into(),
+                name.to_string(),
+            ];
+            for tag in tags {
+                args.extend(["--addtag".into(), tag]);
+            }
+            args.push(format!("{}/{}", vg.name, thin_pool.name));
+
+            lvm::run("lvcreate", args)?;
+
+            LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?
+        } else {
+            vg.add_lv(LVCreateOptions {
                 activate: true,
                 capacity_bytes: capacity,
                 name: name,
-                tags: req.tags,
+                tags,
             })
-            .map_err(map_lvm_error)?;
+            .map_err(map_lvm_error)?
+        };
+
+        // LVM accounts for the volume now that it exists
+        drop(reservation);
+        drop(allocation);
+
+        // Thick volumes get whatever data was left on their extents, so they are zeroed
+        //  before anything is copied onto them. Copies skip zeroed blocks, so they rely
+        //  on this (or on thin volumes reading as zeroes, as their pool zeroes new blocks).
+        let thick = config.thin_pool.is_none();
+        let target = lv.path.clone();
+        let name = full_name.clone();
+
+        // The volume stays in flight until it is filled in, even if the request is dropped
+        let filling = creation.clone();
+        let filled = blocking(move || {
+            let _creation = filling;
+
+            if thick {
+                wipe::zero(&target, None)?;
+            }
+
+            match source {
+                Some((_, Some(snapshot))) => copy_lv(&snapshot.path, &target, &name),
+                Some((source, None)) => copy_lv(&source, &target, &name),
+                None => Ok(()),
+            }
+        })
+        .await;
+
+        // Only reveal the volume once its contents are complete
+        let revealed = filled.and_then(|_| {
+            if pending {
+                lvm::run("lvchange", ["--deltag", PENDING_TAG, &full_name]).map(|_| ())
+            } else {
+                Ok(())
+            }
+        });
+
+        if let Err(status) = revealed {
+            // Do not leave a volume with stale or half-copied data behind
+            if let Err(err) = vg.remove_lv(&lv.name) {
+                log::error!("could not clean up failed volume `{}`: {}", lv.name, err);
+            }
+
+            return Err(status);
+        }
 
-        Ok(Response::new(lv.into()))
+        Ok(Response::new(describe_lv(lv)?))
+    }
+
+    async fn resize_logical_volume(
+        &self,
+        request: Request<ResizeLvRequest>,
+    ) -> Result<Response<LV>, Status> {
+        let req = request.into_inner();
+
+        let name = req
+            .name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        let (lv, vg, config) = self.find_lv(&req.volume_group, &name)?;
+
+        // Finish zeroing the extents added by an earlier attempt, before they can be used
+        finish_zeroing(&lv).await?;
+
+        let current: u64 = (*lv.capacity_bytes).try_into().unwrap_or_default();
+
+        // LVM rounds up to the nearest extent, so the volume may already be large enough
+        let extent_size = lvm::volume_group(&vg.name.to_string())?.extent_size;
+        let size = round_to_extents(req.capacity, extent_size);
+        if size <= current {
+            log::info!(
+                "skipping resize of `{}`, as it is already {} bytes (>= {} requested)",
+                name,
+                current,
+                req.capacity
+            );
+
+            return Ok(Response::new(describe_lv(lv)?));
+        }
+
+        let allocation = self.reservations.lock(&config.name).await;
+        let reservation = self.reserve(&vg, config, size - current).await?;
+
+        // Like new volumes, the extents added to thick volumes must not expose stale data.
+        //  Record where they start first, so that a retry can finish zeroing them.
+        let full_name = format!("{}/{}", vg.name, name);
+        if config.thin_pool.is_none() {
+            lvm::run(
+                "lvchange",
+                [
+                    "--addtag".to_string(),
+                    format!("{}{}", ZERO_FROM_TAG_PREFIX, current),
+                    full_name.clone(),
+                ],
+            )?;
+        }
+
+        lvm::run(
+            "lvextend",
+            ["--size".to_string(), format!("{}b", size), full_name],
+        )?;
+
+        drop(reservation);
+        drop(allocation);
+
+        // Fetch the LV again to get the new size
+        let lv = LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?;
+        finish_zeroing(&lv).await?;
+
+        Ok(Response::new(describe_lv(lv)?))
     }
 
     async fn delete_logical_volume(
         &self,
         request: Request<DeleteLvRequest>,
     ) -> Result<Response<Empty>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap().clone();
         let req = request.into_inner();
 
         let name = req
@@ -116,6 +662,33 @@ impl VolumeService for VolumedServer {
             .try_into()
             .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
 
+        let (lv, vg, config) = self.find_lv(&req.volume_group, &name)?;
+        ensure_unused(&lv)?;
+
+        // Keep the volume around for a while if possible, so that it can be restored
+        if config.trash.is_some() {
+            trash::move_to_trash(&vg.name.to_string(), &lv.name.to_string())?;
+
+            return Ok(Response::new(Empty {}));
+        }
+
+        // Removing the origin of a snapshot would take the snapshot along with it
+        let uuid = lv.uuid.to_string();
+        if lvm::snapshots(&vg.name.to_string())?
+            .iter()
+            .any(|snapshot| snapshot.origin_uuid == uuid)
+        {
+            return Err(Status::failed_precondition(format!(
+                "logical volume `{}` still has snapshots",
+                name
+            )));
+        }
+
+        // Wiping may go over the whole volume, which takes a while
+        let vg_name = vg.name.to_string();
+        let lv_name = name.to_string();
+        let wipe_config = config.clone();
+        blocking(move || wipe::wipe(&vg_name, &lv_name, &wipe_config)).await?;
         vg.remove_lv(&name).map_err(map_lvm_error)?;
 
         Ok(Response::new(Empty {}))
@@ -125,7 +698,6 @@ impl VolumeService for VolumedServer {
         &self,
         request: Request<FormatLvRequest>,
     ) -> Result<Response<Empty>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap().clone();
         let req = request.into_inner();
 
         // Get the resource equivalents of the names
@@ -134,30 +706,99 @@ impl VolumeService for VolumedServer {
             .try_into()
             .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
 
+        // Default to XFS for older clients
+        let fs: Filesystem = if req.fs_type.is_empty() {
+            Filesystem::Xfs
+        } else {
+            req.fs_type.parse()?
+        };
+
         // Get the LV
-        let lv = LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?;
+        let (lv, vg, _) = self.find_lv(&req.volume_group, &name)?;
+        ensure_unused(&lv)?;
 
-        // Format the volume
-        let cmd = std::process::Command::new("mkfs.xfs")
-            .arg("-f")
-            .arg(&lv.path)
-            .output()
-            .map_err(|err| {
-                Status::internal(format!(
-                    "could not run mkfs.xfs command: {}",
-                    err.to_string()
-                ))
-            })?;
+        let options = req.options.unwrap_or_default();
 
-        // Print out the stderr if the command failed
-        if !cmd.status.success() {
-            return Err(Status::internal(format!(
-                "could not format volume `{}`: {}",
-                name,
-                String::from_utf8_lossy(&cmd.stderr)
+        // Encrypted volumes get formatted through a temporary dm-crypt mapping
+        if is_encrypted(&lv)? {
+            if req.passphrase.is_empty() {
+                return Err(Status::invalid_argument(format!(
+                    "volume `{}` is encrypted, but no passphrase was supplied",
+                    name
+                )));
+            }
+
+            let mapping = format!("volumed-{}", lv.uuid);
+            let device = crypt::open(&lv.path, &mapping, &req.passphrase)?;
+            let result = format_device(&name, &device, fs, &options, req.force);
+
+            // The format error is the one worth reporting, a leftover mapping is harmless
+            if let Err(status) = crypt::close(&mapping) {
+                log::error!(
+                    "could not close temporary mapping `{}`: {}",
+                    mapping,
+                    status.message()
+                );
+            }
+            result?;
+        } else {
+            format_device(&name, &lv.path, fs, &options, req.force)?;
+        }
+
+        // Remember what the volume was formatted with
+        lvm::run(
+            "lvchange",
+            [
+                "--addtag".to_string(),
+                format!("{}={}", FS_TYPE_TAG, fs),
+                format!("{}/{}", vg.name, name),
+            ],
+        )?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn encrypt_logical_volume(
+        &self,
+        request: Request<EncryptLvRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let req = request.into_inner();
+
+        // Get the resource equivalents of the names
+        let name = req
+            .name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        if req.passphrase.is_empty() {
+            return Err(Status::invalid_argument(
+                "missing required field `passphrase`",
+            ));
+        }
+
+        // Get the LV
+        let (lv, vg, _) = self.find_lv(&req.volume_group, &name)?;
+
+        // Never clobber existing data, encrypted or not
+        if let Some(signature) = filesystem::probe(&lv.path)? {
+            return Err(Status::already_exists(format!(
+                "volume `{}` already contains a `{}` signature, refusing to encrypt",
+                name, signature
             )));
         }
 
+        crypt::format(&lv.path, &req.passphrase)?;
+
+        // Remember that the volume has to be opened before use
+        lvm::run(
+            "lvchange",
+            [
+                "--addtag".to_string(),
+                ENCRYPTED_TAG.to_string(),
+                format!("{}/{}", vg.name, name),
+            ],
+        )?;
+
         Ok(Response::new(Empty {}))
     }
 
@@ -165,7 +806,6 @@ impl VolumeService for VolumedServer {
         &self,
         request: Request<GetLvRequest>,
     ) -> Result<Response<LV>, Status> {
-        let vg = request.extensions().get::<VolumeGroup>().unwrap().clone();
         let req = request.into_inner();
 
         let id = req.identifier.ok_or(Status::invalid_argument(
@@ -178,18 +818,682 @@ impl VolumeService for VolumedServer {
                     Status::invalid_argument(err.to_string())
                 })?;
 
-                LogicalVolume::from_uuid(&uuid).map_err(map_lvm_error)?
+                let lv = LogicalVolume::from_uuid(&uuid).map_err(map_lvm_error)?;
+
+                // UUIDs are global, so make sure that we actually manage the volume
+                let managed = self
+                    .volume_groups(&req.volume_group)?
+                    .iter()
+                    .any(|(vg, _)| vg.name == lv.volume_group_name);
+                if !managed {
+                    return Err(Status::not_found(format!(
+                        "logical volume `{}` is not in a managed volume group",
+                        lv.uuid
+                    )));
+                }
+                ensure_managed(&lv)?;
+
+                lv
             }
             Identifier::Name(name) => {
                 let name = name.try_into().map_err(|err: InvalidResourceNameError| {
                     Status::invalid_argument(err.to_string())
                 })?;
 
-                LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?
+                self.find_lv(&req.volume_group, &name)?.0
             }
         };
 
-        Ok(Response::new(lv.into()))
+        // Snapshots should only be accessed through the snapshot calls
+        let is_snapshot = lvm::snapshots(&lv.volume_group_name.to_string())?
+            .into_iter()
+            .any(|snapshot| snapshot.uuid == lv.uuid.to_string());
+
+        if is_snapshot {
+            return Err(Status::not_found(format!(
+                "logical volume `{}` is a snapshot",
+                lv.name
+            )));
+        }
+
+        Ok(Response::new(describe_lv(lv)?))
+    }
+
+    async fn adopt_logical_volume(
+        &self,
+        request: Request<AdoptLvRequest>,
+    ) -> Result<Response<LV>, Status> {
+        let req = request.into_inner();
+
+        let name: ResourceName = req
+            .name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        let (lv, vg, config) = self.find_any_lv(&req.volume_group, &name)?;
+
+        // The thin pool holds the data of every thin volume, so it must never be handed out
+        if let Some(thin_pool) = &config.thin_pool {
+            if thin_pool.name == name.to_string() {
+                return Err(Status::invalid_argument(format!(
+                    "logical volume `{}` is the thin pool of volume group `{}`",
+                    name, vg.name
+                )));
+            }
+        }
+
+        log::info!("adopting logical volume `{}/{}`", vg.name, name);
+
+        let mut args = vec![];
+        for tag in req.tags.into_iter().chain([MANAGED_TAG.into()]) {
+            args.extend(["--addtag".to_string(), tag]);
+        }
+        args.push(format!("{}/{}", vg.name, name));
+
+        lvm::run("lvchange", args)?;
+
+        // Fetch the LV again to get the new tags
+        let lv = LogicalVolume::from_id(&vg.name, &lv.name).map_err(map_lvm_error)?;
+
+        Ok(Response::new(describe_lv(lv)?))
+    }
+
+    async fn restore_logical_volume(
+        &self,
+        request: Request<RestoreLvRequest>,
+    ) -> Result<Response<LV>, Status> {
+        let req = request.into_inner();
+
+        for (vg, config) in self.volume_groups(&req.volume_group)? {
+            if config.trash.is_none() {
+                continue;
+            }
+
+            let trashed = trash::trashed(&config.name)?
+                .into_iter()
+                .find(|volume| volume.uuid == req.uuid);
+
+            if let Some(volume) = trashed {
+                trash::restore(&volume)?;
+
+                let name: ResourceName = volume
+                    .original_name
+                    .try_into()
+                    .map_err(|err: InvalidResourceNameError| Status::internal(err.to_string()))?;
+                let lv = LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?;
+
+                return Ok(Response::new(describe_lv(lv)?));
+            }
+        }
+
+        Err(Status::not_found(format!(
+            "could not find deleted logical volume `{}` in the trash of any managed volume group",
+            req.uuid
+        )))
+    }
+
+    async fn get_snapshot_list(
+        &self,
+        request: Request<GetSnapshotListRequest>,
+    ) -> Result<Response<GetSnapshotListResponse>, Status> {
+        let req = request.into_inner();
+
+        let mut snapshots = vec![];
+        for (vg, _) in self.volume_groups(&req.volume_group)? {
+            snapshots.extend(
+                lvm::snapshots(&vg.name.to_string())?
+                    .into_iter()
+                    .filter(is_managed_snapshot)
+                    .filter(|snapshot| {
+                        req.source_uuid.is_empty() || snapshot.origin_uuid == req.source_uuid
+                    })
+                    .map(SnapshotReport::into),
+            );
+        }
+
+        let (snapshots, next_token) = paginate(snapshots, &req.starting_token, req.max_entries)?;
+
+        Ok(Response::new(GetSnapshotListResponse {
+            snapshots,
+            next_token,
+        }))
+    }
+
+    async fn create_snapshot(
+        &self,
+        request: Request<CreateSnapshotRequest>,
+    ) -> Result<Response<Snapshot>, Status> {
+        let req = request.into_inner();
+
+        let name: ResourceName = req
+            .name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        let source_name = req
+            .source_name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        // Make sure that the source exists
+        let (source, vg, config) = self.find_lv(&req.volume_group, &source_name)?;
+
+        // Snapshots of encrypted volumes need the same passphrase, so mark them as well
+        let mut tags = req.tags;
+        if is_encrypted(&source)? {
+            tags.push(ENCRYPTED_TAG.into());
+        }
+
+        // Thin snapshots share the pool with their origin, so they count towards its
+        //  overcommit ratio, while thick snapshots are as large as their origin
+        let _allocation = self.reservations.lock(&config.name).await;
+        let _reservation = self.reserve(&vg, config, lv_size(&source)).await?;
+
+        let mut args = snapshot_args(&name.to_string(), config);
+        for tag in tags.into_iter().chain([MANAGED_TAG.into()]) {
+            args.extend(["--addtag".into(), tag]);
+        }
+        args.push(format!("{}/{}", vg.name, source.name));
+
+        lvm::run("lvcreate", args)?;
+
+        let snapshot = self.find_snapshot(&vg.name.to_string(), |snapshot| {
+            snapshot.name == name.to_string()
+        })?;
+
+        Ok(Response::new(snapshot.into()))
+    }
+
+    async fn delete_snapshot(
+        &self,
+        request: Request<DeleteSnapshotRequest>,
+    ) -> Result<Response<Empty>, Status> {
+        let req = request.into_inner();
+
+        let name: ResourceName = req
+            .name
+            .try_into()
+            .map_err(|err: InvalidResourceNameError| Status::invalid_argument(err.to_string()))?;
+
+        // Make sure that we only ever remove snapshots here
+        let snapshot = self.find_snapshot(&req.volume_group, |snapshot| {
+            snapshot.name == name.to_string()
+        })?;
+
+        let (vg, _) = self.volume_group(&snapshot.volume_group)?;
+
+        // Snapshots may be mounted or opened as well, e.g. while being copied into a clone
+        let lv = LogicalVolume::from_id(&vg.name, &name).map_err(map_lvm_error)?;
+        ensure_unused(&lv)?;
+
+        vg.remove_lv(&name).map_err(map_lvm_error)?;
+
+        Ok(Response::new(Empty {}))
+    }
+
+    async fn get_snapshot(
+        &self,
+        request: Request<GetSnapshotRequest>,
+    ) -> Result<Response<Snapshot>, Status> {
+        let req = request.into_inner();
+
+        let id = req.identifier.ok_or(Status::invalid_argument(
+            "missing required field `identifier`",
+        ))?;
+
+        let snapshot = match id {
+            SnapshotIdentifier::Uuid(uuid) => {
+                self.find_snapshot(&req.volume_group, |snapshot| snapshot.uuid == uuid)?
+            }
+            SnapshotIdentifier::Name(name) => {
+                self.find_snapshot(&req.volume_group, |snapshot| snapshot.name == name)?
+            }
+        };
+
+        Ok(Response::new(snapshot.into()))
+    }
+}
+
+/// Get the page of at most `max_entries` entries (or all of them, if 0) starting at
+/// `starting_token`, along with the token of the next page (empty if there is none).
+///
+/// Full names are unique, so they give a stable order to page over. The token is the
+/// full name of the first entry of the next page, which lets us notice if it was
+/// removed in between requests.
+fn paginate<T: Listed>(
+    mut entries: Vec<T>,
+    starting_token: &str,
+    max_entries: u32,
+) -> Result<(Vec<T>, String), Status> {
+    entries.sort_by(|a, b| (a.name(), a.volume_group()).cmp(&(b.name(), b.volume_group())));
+
+    let start = if starting_token.is_empty() {
+        0
+    } else {
+        if !starting_token.contains('/') {
+            return Err(Status::aborted(format!(
+                "`starting_token` `{}` is not a valid token",
+                starting_token
+            )));
+        }
+
+        entries
+            .iter()
+            .position(|entry| entry.full_name() == starting_token)
+            .ok_or_else(|| {
+                Status::aborted(format!(
+                    "`starting_token` `{}` no longer refers to an existing entry",
+                    starting_token
+                ))
+            })?
+    };
+
+    let end = if max_entries == 0 {
+        entries.len()
+    } else {
+        entries.len().min(start + max_entries as usize)
+    };
+
+    let next_token = entries.get(end).map(Listed::full_name).unwrap_or_default();
+
+    entries.truncate(end);
+    entries.drain(..start);
+
+    Ok((entries, next_token))
+}
+
+/// Bring the volumes created by older releases of the CSI controller under management,
+/// so that they keep working after upgrading.
+///
+/// Note: Only volumes looking exactly like the ones created by those releases are
+/// adopted, so that volumes of other tools using a similar `name=` tag are left alone.
+pub fn adopt_legacy_volumes(vg: &VolumeGroup) -> Result<(), Status> {
+    let mut reports = lvm::lv_reports(&vg.name.to_string())?;
+
+    for lv in vg.list_lvs().map_err(map_lvm_error)? {
+        let tags = reports
+            .remove(&lv.uuid.to_string())
+            .map(|report| report.tags)
+            .unwrap_or_default();
+
+        if !is_legacy_volume(&lv.name.to_string(), &tags) {
+            continue;
+        }
+
+        log::info!(
+            "adopting logical volume `{}/{}` created by an older release",
+            vg.name,
+            lv.name
+        );
+
+        lvm::run(
+            "lvchange",
+            [
+                "--addtag".to_string(),
+                MANAGED_TAG.into(),
+                format!("{}/{}", vg.name, lv.name),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Whether a volume (by name and tags) was created by an older release of the CSI
+/// controller, which named it after a hash and only tagged it with its CSI name
+fn is_legacy_volume(name: &str, tags: &[String]) -> bool {
+    let hashed = !name.is_empty()
+        && name.len() <= LEGACY_NAME_MAX_LEN
+        && name.chars().all(|c| matches!(c, '0'..='9' | 'A'..='F'));
+
+    let tagged = match tags {
+        [tag] => matches!(tag.strip_prefix(LEGACY_NAME_TAG_PREFIX), Some(name) if !name.is_empty()),
+        _ => false,
+    };
+
+    hashed && tagged
+}
+
+/// Run a long blocking operation (e.g. zeroing or copying a whole volume) on a thread
+/// of its own, so that it does not hold up the other requests.
+async fn blocking<F, T>(operation: F) -> Result<T, Status>
+where
+    F: FnOnce() -> Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(operation)
+        .await
+        .unwrap_or_else(|err| {
+            Err(Status::internal(format!(
+                "could not finish blocking operation: {}",
+                err
+            )))
+        })
+}
+
+/// Zero the extents which were added to a thick [LogicalVolume] by a resize, but not
+/// zeroed yet, if any.
+async fn finish_zeroing(lv: &LogicalVolume) -> Result<(), Status> {
+    let tag = match lv_tags(lv)?
+        .into_iter()
+        .find(|tag| tag.starts_with(ZERO_FROM_TAG_PREFIX))
+    {
+        Some(tag) => tag,
+        None => return Ok(()),
+    };
+
+    let offset: u64 = lvm::parse_number(&tag[ZERO_FROM_TAG_PREFIX.len()..])?;
+    let size = lv_size(lv);
+    if offset < size {
+        let device = lv.path.clone();
+        blocking(move || wipe::zero(&device, Some((offset, size - offset)))).await?;
+    }
+
+    lvm::run(
+        "lvchange",
+        [
+            "--deltag".to_string(),
+            tag,
+            format!("{}/{}", lv.volume_group_name, lv.name),
+        ],
+    )
+    .map(|_| ())
+}
+
+/// Copy the contents of `source` into `target` block by block. Zeroed blocks are
+/// skipped, so `target` must already read as zeroes (as fresh volumes do).
+///
+/// Any filesystem on the copy is given a new UUID (and `target_name`, the full name
+/// of the copy, tagged like a formatted volume), so that it can be mounted alongside
+/// the original.
+fn copy_lv(source: &Path, target: &Path, target_name: &str) -> Result<(), Status> {
+    log::info!(
+        "copying contents of `{}` into `{}`",
+        source.to_string_lossy(),
+        target_name
+    );
+
+    // `sparse` skips writing zeroed blocks, which is only correct as the target has been
+    //  zeroed beforehand
+    lvm::run(
+        "dd",
+        [
+            format!("if={}", source.to_string_lossy()),
+            format!("of={}", target.to_string_lossy()),
+            "bs=4M".into(),
+            "conv=sparse,fsync".into(),
+            "status=none".into(),
+        ],
+    )?;
+
+    let fs: Filesystem = match filesystem::probe(target)? {
+        // The filesystem inside of a clone of an encrypted volume keeps its UUID,
+        //  since it cannot be reached without the passphrase
+        Some(signature) if signature == crypt::LUKS_SIGNATURE => {
+            return lvm::run("lvchange", ["--addtag", ENCRYPTED_TAG, target_name]).map(|_| ());
+        }
+        Some(fs_type) => match fs_type.parse() {
+            Ok(fs) => fs,
+            Err(_) => return Ok(()),
+        },
+        None => return Ok(()),
+    };
+
+    fs.regenerate_uuid(target)?;
+    lvm::run(
+        "lvchange",
+        [
+            "--addtag".to_string(),
+            format!("{}={}", FS_TYPE_TAG, fs),
+            target_name.to_string(),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// Arguments to `lvcreate` for a snapshot named `name`, to be followed by its tags
+/// and origin
+fn snapshot_args(name: &str, config: &VolumeGroupConfig) -> Vec<String> {
+    let mut args = vec!["--snapshot".to_string(), "--name".into(), name.to_string()];
+    if config.thin_pool.is_some() {
+        // Thin snapshots are skipped on activation by default
+        args.extend(["--setactivationskip".into(), "n".into()]);
+    } else {
+        // Snapshot the source, reserving as much space as the origin so that the
+        // snapshot can never be invalidated by overflowing
+        args.extend(["--extents".into(), "100%ORIGIN".into()]);
+    }
+
+    args
+}
+
+/// Temporary snapshot of the source of a copy, removed when dropped
+struct SourceSnapshot {
+    /// The full name (`vg/lv`) of the snapshot
+    full_name: String,
+
+    /// The path to the device of the snapshot
+    path: PathBuf,
+}
+
+impl Drop for SourceSnapshot {
+    fn drop(&mut self) {
+        if let Err(status) = lvm::run("lvremove", ["--yes", &self.full_name]) {
+            log::error!(
+                "could not remove temporary snapshot `{}`: {}",
+                self.full_name,
+                status.message()
+            );
+        }
+    }
+}
+
+/// Format a device, refusing to clobber existing data unless forced
+fn format_device(
+    name: &ResourceName,
+    device: &Path,
+    fs: Filesystem,
+    options: &FormatOptions,
+    force: bool,
+) -> Result<(), Status> {
+    // Never clobber existing data unless explicitly asked to
+    if let Some(signature) = filesystem::probe(device)? {
+        if !force {
+            // Let clients tell what is in the way without parsing the message
+            let mut metadata = MetadataMap::new();
+            if let Ok(value) = signature.parse() {
+                metadata.insert(SIGNATURE_METADATA_KEY, value);
+            }
+
+            return Err(Status::with_metadata(
+                Code::AlreadyExists,
+                format!(
+                    "volume `{}` already contains a `{}` signature, refusing to format",
+                    name, signature
+                ),
+                metadata,
+            ));
+        }
+
+        log::warn!(
+            "forcefully formatting volume `{}` over existing `{}` signature",
+            name,
+            signature
+        );
+    }
+
+    // Format the volume, letting mkfs double check for existing data as well
+    let mut mkfs = fs.mkfs(device, options, force)?;
+    let cmd = mkfs
+        .output()
+        .map_err(|err| Status::internal(format!("could not run mkfs.{} command: {}", fs, err)))?;
+
+    // Print out the stderr if the command failed
+    if !cmd.status.success() {
+        return Err(Status::internal(format!(
+            "could not format volume `{}`: {}",
+            name,
+            String::from_utf8_lossy(&cmd.stderr)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refuse access to a [LogicalVolume] not created (or adopted) by volumed, or which
+/// has been deleted.
+fn ensure_managed(lv: &LogicalVolume) -> Result<(), Status> {
+    let reports = lvm::lv_reports(&lv.volume_group_name.to_string())?;
+    let tags = reports
+        .get(&lv.uuid.to_string())
+        .map(|report| report.tags.as_slice())
+        .unwrap_or_default();
+
+    if !tags.iter().any(|tag| tag == MANAGED_TAG) {
+        return Err(Status::failed_precondition(format!(
+            "logical volume `{}/{}` is not managed by volumed: adopt it first",
+            lv.volume_group_name, lv.name
+        )));
+    }
+
+    if trash::is_trashed(tags) {
+        return Err(Status::not_found(format!(
+            "logical volume `{}/{}` has been deleted",
+            lv.volume_group_name, lv.name
+        )));
+    }
+
+    if tags.iter().any(|tag| tag == PENDING_TAG) {
+        return Err(Status::not_found(format!(
+            "logical volume `{}/{}` is still being created",
+            lv.volume_group_name, lv.name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Refuse to touch the data of a [LogicalVolume] which is in use, e.g. because it is
+/// still mounted.
+fn ensure_unused(lv: &LogicalVolume) -> Result<(), Status> {
+    let mountpoints = mounts::mountpoints(&lv.path)?;
+    if !mountpoints.is_empty() {
+        return Err(Status::failed_precondition(format!(
+            "logical volume `{}/{}` is in use: mounted at {}",
+            lv.volume_group_name,
+            lv.name,
+            mountpoints
+                .iter()
+                .map(|mountpoint| format!("`{}`", mountpoint.to_string_lossy()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )));
+    }
+
+    // The device may still be held open without being mounted (e.g. by a process
+    //  or another device on top of it)
+    let reports = lvm::lv_reports(&lv.volume_group_name.to_string())?;
+    let open = reports
+        .get(&lv.uuid.to_string())
+        .map(|report| report.open)
+        .unwrap_or_default();
+
+    if open {
+        return Err(Status::failed_precondition(format!(
+            "logical volume `{}/{}` is in use: its device is open",
+            lv.volume_group_name, lv.name
+        )));
+    }
+
+    Ok(())
+}
+
+/// Whether a snapshot was created (or adopted) by volumed
+fn is_managed_snapshot(snapshot: &SnapshotReport) -> bool {
+    snapshot.tags.iter().any(|tag| tag == MANAGED_TAG)
+}
+
+/// Check whether a [LogicalVolume] has been encrypted with LUKS
+fn is_encrypted(lv: &LogicalVolume) -> Result<bool, Status> {
+    Ok(lv_tags(lv)?.iter().any(|tag| tag == ENCRYPTED_TAG))
+}
+
+/// Get the tags of a [LogicalVolume], which are not tracked by [lvm2_cmd]
+fn lv_tags(lv: &LogicalVolume) -> Result<Vec<String>, Status> {
+    let mut reports = lvm::lv_reports(&lv.volume_group_name.to_string())?;
+
+    Ok(reports
+        .remove(&lv.uuid.to_string())
+        .map(|report| report.tags)
+        .unwrap_or_default())
+}
+
+/// Get the size of a [LogicalVolume] in bytes
+fn lv_size(lv: &LogicalVolume) -> u64 {
+    (*lv.capacity_bytes).try_into().unwrap_or_default()
+}
+
+/// Convert a [LogicalVolume] into its message form, including its tags and health
+fn describe_lv(lv: LogicalVolume) -> Result<LV, Status> {
+    let mut reports = lvm::lv_reports(&lv.volume_group_name.to_string())?;
+    let reply: LV = lv.into();
+    let report = reports.remove(&reply.uuid).unwrap_or_default();
+
+    Ok(with_report(reply, report))
+}
+
+/// Fill in the details of an [LV] which are not tracked by [lvm2_cmd]
+fn with_report(lv: LV, report: LvReport) -> LV {
+    LV {
+        tags: report.tags,
+        active: report.active,
+        open: report.open,
+        partial: report.partial,
+        health: report.health,
+        ..lv
+    }
+}
+
+/// Load a [VolumeGroup] by name
+fn load_volume_group(name: &str) -> Result<VolumeGroup, Status> {
+    let resource: ResourceName = name
+        .to_string()
+        .try_into()
+        .map_err(|err: InvalidResourceNameError| Status::internal(err.to_string()))?;
+
+    VolumeGroup::from_id(&resource)
+        .map_err(|err| Status::internal(format!("could not load volume group `{}`: {}", name, err)))
+}
+
+/// An entry of a listing that can be paginated over
+trait Listed {
+    fn name(&self) -> &str;
+    fn volume_group(&self) -> &str;
+
+    /// The full name (`vg/name`) of the entry, which is unique across volume groups
+    fn full_name(&self) -> String {
+        format!("{}/{}", self.volume_group(), self.name())
+    }
+}
+
+impl Listed for LV {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn volume_group(&self) -> &str {
+        &self.volume_group
+    }
+}
+
+impl Listed for Snapshot {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn volume_group(&self) -> &str {
+        &self.volume_group
     }
 }
 
@@ -201,6 +1505,27 @@ impl From<LogicalVolume> for LV {
             // TODO: What do we do if the capacity is larger than a u64?
             capacity_bytes: (*lv.capacity_bytes).try_into().unwrap_or_default(),
             volume_group: lv.volume_group_name.to_string(),
+
+            // These are not tracked by lvm2_cmd, so they must be filled in separately
+            tags: vec![],
+            active: false,
+            open: false,
+            partial: false,
+            health: String::new(),
+        }
+    }
+}
+
+impl From<SnapshotReport> for Snapshot {
+    fn from(snapshot: SnapshotReport) -> Self {
+        Snapshot {
+            uuid: snapshot.uuid,
+            name: snapshot.name,
+            capacity_bytes: snapshot.origin_size,
+            volume_group: snapshot.volume_group,
+            source_uuid: snapshot.origin_uuid,
+            creation_time: snapshot.creation_time,
+            tags: snapshot.tags,
         }
     }
 }
@@ -214,3 +1539,137 @@ fn map_lvm_error(err: LVMError) -> Status {
         _ => Status::internal(err.to_string()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn volumes(names: &[&str]) -> Vec<LV> {
+        names
+            .iter()
+            .map(|name| LV {
+                name: name.to_string(),
+                volume_group: "volumes".into(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    fn names(volumes: &[LV]) -> Vec<&str> {
+        volumes.iter().map(|lv| lv.name.as_str()).collect()
+    }
+
+    #[test]
+    fn adopts_only_legacy_volumes() {
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<_>>();
+
+        for (name, volume_tags, legacy) in [
+            ("9F86D081884C7D65", tags(&["name=pvc-1"]), true),
+            ("1A", tags(&["name=pvc-1"]), true),
+            // Already managed, or tagged by something else as well
+            (
+                "9F86D081884C7D65",
+                tags(&["name=pvc-1", MANAGED_TAG]),
+                false,
+            ),
+            ("9F86D081884C7D65", tags(&["name=pvc-1", "owner=me"]), false),
+            // Not named like a legacy volume
+            ("data", tags(&["name=pvc-1"]), false),
+            ("9f86d081884c7d65", tags(&["name=pvc-1"]), false),
+            ("9F86D081884C7D65A", tags(&["name=pvc-1"]), false),
+            ("", tags(&["name=pvc-1"]), false),
+            // Not tagged like a legacy volume
+            ("9F86D081884C7D65", tags(&[]), false),
+            ("9F86D081884C7D65", tags(&["name="]), false),
+            ("9F86D081884C7D65", tags(&["csi-name=70766331"]), false),
+        ] {
+            assert_eq!(
+                is_legacy_volume(name, &volume_tags),
+                legacy,
+                "{} {:?}",
+                name,
+                volume_tags
+            );
+        }
+    }
+
+    #[test]
+    fn paginate_empty() {
+        let (page, next_token) = paginate::<LV>(vec![], "", 2).unwrap();
+
+        assert!(page.is_empty());
+        assert_eq!(next_token, "");
+    }
+
+    #[test]
+    fn paginate_all_without_max_entries() {
+        let (page, next_token) = paginate(volumes(&["c", "a", "b"]), "", 0).unwrap();
+
+        assert_eq!(names(&page), ["a", "b", "c"]);
+        assert_eq!(next_token, "");
+    }
+
+    #[test]
+    fn paginate_exact_multiple() {
+        let all = volumes(&["d", "c", "b", "a"]);
+
+        let (page, next_token) = paginate(all.clone(), "", 2).unwrap();
+        assert_eq!(names(&page), ["a", "b"]);
+        assert_eq!(next_token, "volumes/c");
+
+        // The last page is full, but there is nothing after it
+        let (page, next_token) = paginate(all, &next_token, 2).unwrap();
+        assert_eq!(names(&page), ["c", "d"]);
+        assert_eq!(next_token, "");
+    }
+
+    #[test]
+    fn paginate_partial_last_page() {
+        let (page, next_token) = paginate(volumes(&["a", "b", "c"]), "volumes/c", 2).unwrap();
+
+        assert_eq!(names(&page), ["c"]);
+        assert_eq!(next_token, "");
+    }
+
+    #[test]
+    fn paginate_stale_token() {
+        let status = paginate(volumes(&["a", "c"]), "volumes/b", 2).unwrap_err();
+
+        assert_eq!(status.code(), Code::Aborted);
+    }
+
+    #[test]
+    fn paginate_invalid_token() {
+        let status = paginate(volumes(&["a", "b"]), "a", 2).unwrap_err();
+
+        assert_eq!(status.code(), Code::Aborted);
+    }
+
+    #[test]
+    fn paginate_snapshots_across_volume_groups() {
+        let snapshots: Vec<_> = [("ssd", "b"), ("hdd", "a"), ("hdd", "b")]
+            .into_iter()
+            .map(|(volume_group, name)| Snapshot {
+                name: name.into(),
+                volume_group: volume_group.into(),
+                ..Default::default()
+            })
+            .collect();
+
+        let (page, next_token) = paginate(snapshots.clone(), "", 2).unwrap();
+        assert_eq!(
+            page.iter().map(Listed::full_name).collect::<Vec<_>>(),
+            ["hdd/a", "hdd/b"]
+        );
+        assert_eq!(next_token, "ssd/b");
+
+        let (page, next_token) = paginate(snapshots, &next_token, 2).unwrap();
+        assert_eq!(
+            page.iter().map(Listed::full_name).collect::<Vec<_>>(),
+            ["ssd/b"]
+        );
+        assert_eq!(next_token, "");
+    }
+}
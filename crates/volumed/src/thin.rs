@@ -0,0 +1,175 @@
+//! Helpers for allocating volumes from a thin pool.
+
+use std::time::Duration;
+
+use crate::{
+    lvm::{self, ThinPoolReport},
+    ThinPoolConfig,
+};
+
+/// How often to check whether the thin pool needs to be extended
+const AUTOEXTEND_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The maximum virtual size of all thin volumes in the pool, as allowed by the
+/// overcommit ratio
+pub fn max_virtual_bytes(config: &ThinPoolConfig, report: &ThinPoolReport) -> u64 {
+    (report.data_bytes as f64 * config.max_overcommit_ratio) as u64
+}
+
+/// The virtual size still available for thin volumes in the pool
+pub fn free_virtual_bytes(config: &ThinPoolConfig, report: &ThinPoolReport) -> u64 {
+    max_virtual_bytes(config, report).saturating_sub(report.virtual_bytes)
+}
+
+/// Periodically extend the thin pool whenever its data or metadata usage goes
+/// over the configured threshold, without using up the `spare_bytes` of the
+/// volume group.
+///
+/// Note: This never returns, so it should be spawned as its own task.
+pub async fn autoextend(
+    volume_group: String,
+    config: ThinPoolConfig,
+    threshold: u8,
+    spare_bytes: u64,
+) {
+    let threshold = threshold as f64;
+    let mut interval = tokio::time::interval(AUTOEXTEND_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let report = match lvm::thin_pool(&volume_group, &config.name) {
+            Ok(report) => report,
+            Err(status) => {
+                log::error!(
+                    "could not check usage of thin pool `{}`: {}",
+                    config.name,
+                    status.message()
+                );
+                continue;
+            }
+        };
+
+        let available = match lvm::volume_group(&volume_group) {
+            Ok(report) => report.free_bytes.saturating_sub(spare_bytes),
+            Err(status) => {
+                log::error!(
+                    "could not check free space of volume group `{}`: {}",
+                    volume_group,
+                    status.message()
+                );
+                continue;
+            }
+        };
+
+        let pool = format!("{}/{}", volume_group, config.name);
+        let grow_by = |bytes: u64| (bytes * config.autoextend_percent as u64 / 100).min(available);
+
+        if available == 0
+            && (report.data_percent >= threshold || report.metadata_percent >= threshold)
+        {
+            log::warn!(
+                "not extending thin pool `{}`: volume group `{}` has no space left beyond its spare bytes",
+                pool,
+                volume_group
+            );
+            continue;
+        }
+
+        if report.data_percent >= threshold {
+            log::info!(
+                "extending data of thin pool `{}` by {}%, as it is {}% full",
+                pool,
+                config.autoextend_percent,
+                report.data_percent
+            );
+
+            if let Err(status) = lvm::run(
+                "lvextend",
+                [
+                    "--size".to_string(),
+                    format!("+{}b", grow_by(report.data_bytes)),
+                    pool.clone(),
+                ],
+            ) {
+                log::error!(
+                    "could not extend thin pool `{}`: {}",
+                    pool,
+                    status.message()
+                );
+            }
+        }
+
+        if report.metadata_percent >= threshold {
+            log::info!(
+                "extending metadata of thin pool `{}` by {}%, as it is {}% full",
+                pool,
+                config.autoextend_percent,
+                report.metadata_percent
+            );
+
+            if let Err(status) = lvm::run(
+                "lvextend",
+                [
+                    "--poolmetadatasize".to_string(),
+                    format!("+{}b", grow_by(report.metadata_bytes)),
+                    pool.clone(),
+                ],
+            ) {
+                log::error!(
+                    "could not extend metadata of thin pool `{}`: {}",
+                    pool,
+                    status.message()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(max_overcommit_ratio: f64) -> ThinPoolConfig {
+        ThinPoolConfig {
+            name: "pool".into(),
+            max_overcommit_ratio,
+            autoextend_threshold: None,
+            autoextend_percent: 20,
+            enable_zeroing: false,
+        }
+    }
+
+    fn report(data_bytes: u64, virtual_bytes: u64) -> ThinPoolReport {
+        ThinPoolReport {
+            data_bytes,
+            data_percent: 0.0,
+            metadata_bytes: 0,
+            metadata_percent: 0.0,
+            virtual_bytes,
+            zero_new_blocks: true,
+        }
+    }
+
+    #[test]
+    fn overcommit_limits_virtual_bytes() {
+        for (ratio, data_bytes, virtual_bytes, max, free) in [
+            (1.0, 1000, 0, 1000, 1000),
+            (1.0, 1000, 400, 1000, 600),
+            (1.0, 1000, 1000, 1000, 0),
+            (2.5, 1000, 1000, 2500, 1500),
+            (2.5, 1000, 2500, 2500, 0),
+        ] {
+            let (config, report) = (config(ratio), report(data_bytes, virtual_bytes));
+
+            assert_eq!(max_virtual_bytes(&config, &report), max);
+            assert_eq!(free_virtual_bytes(&config, &report), free);
+        }
+    }
+
+    #[test]
+    fn overcommitted_pools_have_nothing_free() {
+        // E.g. after lowering the ratio below the current usage
+        assert_eq!(free_virtual_bytes(&config(1.0), &report(1000, 1500)), 0);
+    }
+}
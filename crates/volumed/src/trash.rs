@@ -0,0 +1,480 @@
+//! Soft deletion of logical volumes.
+//!
+//! Instead of being removed right away, deleted volumes are renamed out of the way
+//! and tagged with their original name and deletion time. They are hidden from
+//! everything but [restore] until they are purged, either once their retention
+//! period is over or earlier if their space is needed for new allocations.
+
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tonic::Status;
+
+use crate::{lvm, server::MANAGED_TAG, wipe, VolumeGroupConfig};
+
+/// How often to check for deleted volumes past their retention period
+const PURGE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// Prefix of the names of deleted volumes
+const TRASH_NAME_PREFIX: &str = "trash-";
+
+/// Prefix of the tag holding the time (in seconds since the UNIX epoch) at which a
+/// volume was deleted
+const DELETED_AT_TAG_PREFIX: &str = "deleted-at=";
+
+/// Prefix of the tag holding the name of a volume before it was deleted
+const DELETED_NAME_TAG_PREFIX: &str = "deleted-name=";
+
+/// A deleted volume which has not been purged yet
+#[derive(Clone, Debug)]
+pub struct TrashedVolume {
+    pub uuid: String,
+    pub name: String,
+    pub volume_group: String,
+    pub size: u64,
+
+    /// The name of the volume before it was deleted
+    pub original_name: String,
+
+    /// Seconds since the UNIX epoch
+    pub deleted_at: i64,
+}
+
+/// Whether the tags of a volume mark it as deleted
+pub fn is_trashed(tags: &[String]) -> bool {
+    tags.iter()
+        .any(|tag| tag.starts_with(DELETED_AT_TAG_PREFIX))
+}
+
+/// Move a volume to the trash, freeing up its name
+pub fn move_to_trash(volume_group: &str, name: &str) -> Result<(), Status> {
+    let uuid = lvm::report("lvs", &["lv_uuid"], &format!("{}/{}", volume_group, name))?
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_iter().next())
+        .ok_or_else(|| {
+            Status::not_found(format!(
+                "could not find logical volume `{}/{}`",
+                volume_group, name
+            ))
+        })?;
+
+    trash_volume(volume_group, name, &uuid, now(), |program, args| {
+        lvm::run(program, args)
+    })?;
+
+    log::info!(
+        "moved logical volume `{}/{}` to the trash",
+        volume_group,
+        name
+    );
+
+    Ok(())
+}
+
+/// Tag a volume as deleted and rename it out of the way, running the commands with
+/// `run` (see [lvm::run]).
+///
+/// The volume is tagged first, so that it stays hidden if we are interrupted before
+/// renaming it (see [restore]). If renaming it fails, the tags are removed again.
+fn trash_volume<F>(
+    volume_group: &str,
+    name: &str,
+    uuid: &str,
+    deleted_at: i64,
+    mut run: F,
+) -> Result<(), Status>
+where
+    F: FnMut(&str, Vec<String>) -> Result<String, Status>,
+{
+    let tags = [
+        format!("{}{}", DELETED_AT_TAG_PREFIX, deleted_at),
+        format!("{}{}", DELETED_NAME_TAG_PREFIX, name),
+    ];
+    let tag_args = |flag: &str| {
+        let mut args: Vec<String> = tags
+            .iter()
+            .flat_map(|tag| [flag.to_string(), tag.clone()])
+            .collect();
+        args.push(format!("{}/{}", volume_group, name));
+
+        args
+    };
+
+    run("lvchange", tag_args("--addtag"))?;
+
+    let renamed = run(
+        "lvrename",
+        vec![
+            volume_group.to_string(),
+            name.to_string(),
+            format!("{}{}", TRASH_NAME_PREFIX, uuid),
+        ],
+    );
+
+    if let Err(status) = renamed {
+        if let Err(err) = run("lvchange", tag_args("--deltag")) {
+            log::error!(
+                "could not untag logical volume `{}/{}` after failing to move it to the trash: {}",
+                volume_group,
+                name,
+                err.message()
+            );
+        }
+
+        return Err(status);
+    }
+
+    Ok(())
+}
+
+/// Restore a deleted volume under its original name
+pub fn restore(volume: &TrashedVolume) -> Result<(), Status> {
+    // The volume may not have been renamed if moving it to the trash was interrupted
+    if volume.name != volume.original_name {
+        let taken = lvm::report("lvs", &["lv_name"], &volume.volume_group)?
+            .into_iter()
+            .any(|row| row.first() == Some(&volume.original_name));
+
+        if taken {
+            return Err(Status::already_exists(format!(
+                "cannot restore logical volume `{}`: its name `{}` has been taken in the meantime",
+                volume.uuid, volume.original_name
+            )));
+        }
+
+        lvm::run(
+            "lvrename",
+            [
+                volume.volume_group.clone(),
+                volume.name.clone(),
+                volume.original_name.clone(),
+            ],
+        )?;
+    }
+
+    lvm::run(
+        "lvchange",
+        [
+            "--deltag".to_string(),
+            format!("{}{}", DELETED_AT_TAG_PREFIX, volume.deleted_at),
+            "--deltag".into(),
+            format!("{}{}", DELETED_NAME_TAG_PREFIX, volume.original_name),
+            format!("{}/{}", volume.volume_group, volume.original_name),
+        ],
+    )?;
+
+    log::info!(
+        "restored logical volume `{}/{}` from the trash",
+        volume.volume_group,
+        volume.original_name
+    );
+
+    Ok(())
+}
+
+/// List the deleted volumes of a volume group, oldest first
+pub fn trashed(volume_group: &str) -> Result<Vec<TrashedVolume>, Status> {
+    let rows = lvm::report(
+        "lvs",
+        &["lv_uuid", "lv_name", "lv_size", "lv_tags"],
+        volume_group,
+    )?;
+
+    let mut volumes = vec![];
+    for row in rows.iter().filter(|row| row.len() == 4) {
+        if let Some(volume) = parse_trashed(volume_group, row)? {
+            volumes.push(volume);
+        }
+    }
+
+    volumes.sort_by_key(|volume| volume.deleted_at);
+
+    Ok(volumes)
+}
+
+/// Parse a row of the report in [trashed], returning [None] if the volume is not in
+/// the trash
+fn parse_trashed(volume_group: &str, row: &[String]) -> Result<Option<TrashedVolume>, Status> {
+    let tags: Vec<&str> = row[3].split(',').collect();
+    let tag = |prefix: &str| tags.iter().find_map(|tag| tag.strip_prefix(prefix));
+
+    // Only volumes which volumed deleted itself belong to the trash
+    if !tags.contains(&MANAGED_TAG) {
+        return Ok(None);
+    }
+
+    match (tag(DELETED_AT_TAG_PREFIX), tag(DELETED_NAME_TAG_PREFIX)) {
+        (Some(deleted_at), Some(original_name)) => Ok(Some(TrashedVolume {
+            uuid: row[0].clone(),
+            name: row[1].clone(),
+            volume_group: volume_group.to_string(),
+            size: lvm::parse_number(&row[2])?,
+            original_name: original_name.to_string(),
+            deleted_at: lvm::parse_number(deleted_at)?,
+        })),
+        _ => Ok(None),
+    }
+}
+
+/// Whether a deleted volume is past its retention period at `now` (in seconds since
+/// the UNIX epoch)
+fn is_expired(volume: &TrashedVolume, retention: Duration, now: i64) -> bool {
+    volume.deleted_at <= now - retention.as_secs() as i64
+}
+
+/// List the deleted volumes of a volume group which can be purged, oldest first.
+///
+/// Note: Volumes with snapshots are kept, since removing them would take their
+/// snapshots along.
+pub fn purgeable(volume_group: &str) -> Result<Vec<TrashedVolume>, Status> {
+    let origins: HashSet<String> = lvm::snapshots(volume_group)?
+        .into_iter()
+        .map(|snapshot| snapshot.origin_uuid)
+        .collect();
+
+    Ok(trashed(volume_group)?
+        .into_iter()
+        .filter(|volume| !origins.contains(&volume.uuid))
+        .collect())
+}
+
+/// Purge the oldest deleted volumes of a volume group until at least `bytes` have
+/// been freed, returning how many bytes actually were.
+pub fn reclaim(config: &VolumeGroupConfig, bytes: u64) -> Result<u64, Status> {
+    let mut freed = 0;
+    for volume in purgeable(&config.name)? {
+        if freed >= bytes {
+            break;
+        }
+
+        log::info!(
+            "purging logical volume `{}` from the trash early to make room",
+            volume.uuid
+        );
+
+        match purge_volume(&volume, config) {
+            Ok(()) => freed += volume.size,
+            Err(status) => log::error!(
+                "could not purge logical volume `{}`: {}",
+                volume.uuid,
+                status.message()
+            ),
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Periodically purge the deleted volumes of a volume group which are past their
+/// retention period.
+///
+/// Note: This never returns, so it should be spawned as its own task.
+pub async fn purge(config: VolumeGroupConfig, retention: Duration) {
+    let mut interval = tokio::time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let volumes = match purgeable(&config.name) {
+            Ok(volumes) => volumes,
+            Err(status) => {
+                log::error!(
+                    "could not list the trash of volume group `{}`: {}",
+                    config.name,
+                    status.message()
+                );
+                continue;
+            }
+        };
+
+        let now = now();
+        for volume in volumes
+            .into_iter()
+            .filter(|volume| is_expired(volume, retention, now))
+        {
+            log::info!(
+                "purging logical volume `{}` ({}) from the trash",
+                volume.uuid,
+                volume.original_name
+            );
+
+            // Wiping the volume may take a while, so keep it off of the runtime
+            let uuid = volume.uuid.clone();
+            let purge_config = config.clone();
+            let result = tokio::task::spawn_blocking(move || purge_volume(&volume, &purge_config))
+                .await
+                .unwrap_or_else(|err| Err(Status::internal(err.to_string())));
+
+            if let Err(status) = result {
+                log::error!(
+                    "could not purge logical volume `{}`: {}",
+                    uuid,
+                    status.message()
+                );
+            }
+        }
+    }
+}
+
+/// Wipe and remove a deleted volume (see [purgeable])
+fn purge_volume(volume: &TrashedVolume, config: &VolumeGroupConfig) -> Result<(), Status> {
+    wipe::wipe(&volume.volume_group, &volume.name, config)?;
+    lvm::run(
+        "lvremove",
+        [
+            "--yes".to_string(),
+            format!("{}/{}", volume.volume_group, volume.name),
+        ],
+    )?;
+
+    Ok(())
+}
+
+/// The current time in seconds since the UNIX epoch
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::Code;
+
+    use super::*;
+
+    fn row(tags: &str) -> Vec<String> {
+        ["uuid", "trash-uuid", "1048576", tags]
+            .map(str::to_string)
+            .to_vec()
+    }
+
+    fn volume(deleted_at: i64) -> TrashedVolume {
+        TrashedVolume {
+            uuid: "uuid".into(),
+            name: "trash-uuid".into(),
+            volume_group: "volumes".into(),
+            size: 0,
+            original_name: "data".into(),
+            deleted_at,
+        }
+    }
+
+    #[test]
+    fn parses_trashed_volumes() {
+        let volume = parse_trashed(
+            "volumes",
+            &row("managed-by=volumed,deleted-at=1700000000,deleted-name=data"),
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(volume.uuid, "uuid");
+        assert_eq!(volume.name, "trash-uuid");
+        assert_eq!(volume.volume_group, "volumes");
+        assert_eq!(volume.size, 1048576);
+        assert_eq!(volume.original_name, "data");
+        assert_eq!(volume.deleted_at, 1700000000);
+    }
+
+    #[test]
+    fn skips_volumes_outside_of_the_trash() {
+        for tags in [
+            "",
+            "managed-by=volumed",
+            "managed-by=volumed,deleted-at=1700000000",
+            "managed-by=volumed,deleted-name=data",
+            // Deleted by someone else
+            "deleted-at=1700000000,deleted-name=data",
+        ] {
+            assert!(
+                parse_trashed("volumes", &row(tags)).unwrap().is_none(),
+                "{}",
+                tags
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_deletion_times() {
+        let status = parse_trashed(
+            "volumes",
+            &row("managed-by=volumed,deleted-at=yesterday,deleted-name=data"),
+        )
+        .unwrap_err();
+
+        assert_eq!(status.code(), Code::Internal);
+    }
+
+    #[test]
+    fn expires_after_retention() {
+        let retention = Duration::from_secs(60);
+
+        for (deleted_at, now, expired) in [
+            (1000, 1000, false),
+            (1000, 1059, false),
+            (1000, 1060, true),
+            (1000, 5000, true),
+        ] {
+            assert_eq!(
+                is_expired(&volume(deleted_at), retention, now),
+                expired,
+                "deleted at {}, now {}",
+                deleted_at,
+                now
+            );
+        }
+    }
+
+    #[test]
+    fn tags_then_renames_into_the_trash() {
+        let mut calls = vec![];
+        trash_volume("volumes", "data", "uuid", 1700000000, |program, args| {
+            calls.push(format!("{} {}", program, args.join(" ")));
+            Ok(String::new())
+        })
+        .unwrap();
+
+        assert_eq!(
+            calls,
+            [
+                "lvchange --addtag deleted-at=1700000000 --addtag deleted-name=data volumes/data",
+                "lvrename volumes data trash-uuid",
+            ]
+        );
+    }
+
+    #[test]
+    fn untags_when_renaming_fails() {
+        let mut calls = vec![];
+        let status = trash_volume("volumes", "data", "uuid", 1700000000, |program, args| {
+            calls.push(format!("{} {}", program, args.join(" ")));
+            match program {
+                "lvrename" => Err(Status::internal("lvrename command failed")),
+                _ => Ok(String::new()),
+            }
+        })
+        .unwrap_err();
+
+        assert_eq!(status.message(), "lvrename command failed");
+        assert_eq!(
+            calls,
+            [
+                "lvchange --addtag deleted-at=1700000000 --addtag deleted-name=data volumes/data",
+                "lvrename volumes data trash-uuid",
+                "lvchange --deltag deleted-at=1700000000 --deltag deleted-name=data volumes/data",
+            ]
+        );
+    }
+
+    #[test]
+    fn detects_trashed_tags() {
+        assert!(is_trashed(&["deleted-at=1700000000".into()]));
+        assert!(!is_trashed(&["managed-by=volumed".into()]));
+        assert!(!is_trashed(&[]));
+    }
+}
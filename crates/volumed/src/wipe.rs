@@ -0,0 +1,62 @@
+//! Helpers for clearing the data of logical volumes.
+
+use std::path::Path;
+
+use tonic::Status;
+
+use crate::{lvm, VolumeGroupConfig, WipePolicy};
+
+/// Wipe a volume before it is removed, according to the policy of its volume group.
+///
+/// Note: Thin volumes are always discarded instead of zeroed, since the blocks
+/// returned to the pool read as zeroes once provisioned again and zeroing them
+/// would needlessly provision the whole volume.
+pub fn wipe(volume_group: &str, name: &str, config: &VolumeGroupConfig) -> Result<(), Status> {
+    if config.wipe_policy == WipePolicy::None {
+        return Ok(());
+    }
+
+    // Inactive volumes have no device to wipe through
+    let device = Path::new("/dev").join(volume_group).join(name);
+    if !device.exists() {
+        lvm::run(
+            "lvchange",
+            [
+                "--activate".to_string(),
+                "y".into(),
+                format!("{}/{}", volume_group, name),
+            ],
+        )?;
+    }
+
+    match (config.wipe_policy, &config.thin_pool) {
+        (WipePolicy::None, _) => Ok(()),
+        (WipePolicy::Discard, _) | (WipePolicy::Zero, Some(_)) => discard(&device),
+        (WipePolicy::Zero, None) => zero(&device, None),
+    }
+}
+
+/// Discard all blocks of a device
+pub fn discard(device: &Path) -> Result<(), Status> {
+    lvm::run("blkdiscard", [device.as_os_str()]).map(|_| ())
+}
+
+/// Overwrite a device with zeroes, either entirely or only the `(offset, length)`
+/// range in bytes.
+///
+/// Note: This lets the device offload the zeroing where supported, instead of
+/// writing out every block.
+pub fn zero(device: &Path, range: Option<(u64, u64)>) -> Result<(), Status> {
+    let mut args = vec!["--zeroout".to_string()];
+    if let Some((offset, length)) = range {
+        args.extend([
+            "--offset".into(),
+            offset.to_string(),
+            "--length".into(),
+            length.to_string(),
+        ]);
+    }
+    args.push(device.to_string_lossy().to_string());
+
+    lvm::run("blkdiscard", args).map(|_| ())
+}